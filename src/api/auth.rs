@@ -0,0 +1,276 @@
+use super::error::{Error, ErrorCode, Result};
+use super::message::ServerMessage;
+use actix_web::dev::{Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::http::{header, Method};
+use actix_web::{HttpMessage, HttpRequest, HttpResponse};
+use futures::future::{ok, Either, Ready};
+use log::info;
+use qstring::QString;
+use serde::{Deserialize, Serialize};
+use shiromana_rs::misc::Uuid;
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+// Routes which could touch arbitrary paths on the disk of server
+const ADMIN_ROUTES: [&str; 8] = [
+    "library/open",
+    "library/create",
+    "library/close",
+    // Could close library as `library/close`
+    "library/forget",
+    // Lists paths of every registered library
+    "library/registry",
+    "media/add",
+    // Server posts to any url given
+    "webhook/create",
+    "webhook/delete",
+];
+
+// Routes describing apis, open to anyone
+const PUBLIC_ROUTES: [&str; 2] = ["openapi.json", "docs"];
+
+// Routes taking token from param `access_token`, for `EventSource` which cannot set
+// headers. Only these, as query of request ends up in access logs.
+pub const QUERY_TOKEN_ROUTES: [&str; 1] = ["events"];
+
+// Routes covering every library unless param `library` is given
+const ALL_LIBRARY_ROUTES: [&str; 3] = ["events", "jobs/list", "status"];
+
+#[derive(Deserialize, Serialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub enum Permission {
+    ReadOnly,
+    ReadWrite,
+    Admin,
+}
+
+#[derive(Deserialize, Serialize, Clone)]
+pub struct Token {
+    pub token: String,
+    #[serde(default)]
+    pub name: Option<String>,
+    pub permission: Permission,
+    // Restrict token to these libraries, all libraries if not provided
+    #[serde(default)]
+    pub libraries: Option<Vec<Uuid>>,
+}
+
+// Libraries of the token of request, for apis which only know their library after
+// looking it up, like `upload/{id}`. Not set if authentication is disabled.
+#[derive(Clone)]
+struct TokenLibraries(Option<Vec<Uuid>>);
+
+pub fn check_library(req: &HttpRequest, library: &Uuid) -> Result<()> {
+    match req.extensions().get::<TokenLibraries>() {
+        Some(TokenLibraries(Some(libraries))) if !libraries.contains(library) => {
+            Err(Error::Forbidden(format!(
+                "Token is not allowed to access library `{}`.",
+                library
+            )))
+        }
+        _ => Ok(()),
+    }
+}
+
+#[derive(Default)]
+pub struct TokenStore {
+    tokens: HashMap<String, Token>,
+}
+
+impl TokenStore {
+    // Load tokens from a json file, which is an array of `Token`
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let tokens: Vec<Token> = serde_json::from_slice(&std::fs::read(path)?)?;
+        Ok(Self {
+            tokens: tokens.into_iter().map(|v| (v.token.clone(), v)).collect(),
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.tokens.len()
+    }
+
+    pub fn get(&self, token: &str) -> Option<&Token> {
+        self.tokens.get(token)
+    }
+}
+
+// Bearer token authentication for the api scope
+pub struct TokenAuth {
+    tokens: Arc<TokenStore>,
+}
+
+impl TokenAuth {
+    pub fn new(tokens: Arc<TokenStore>) -> Self {
+        Self { tokens }
+    }
+}
+
+impl<S> Transform<S> for TokenAuth
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse, Error = actix_web::Error>,
+    S::Future: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse;
+    type Error = actix_web::Error;
+    type InitError = ();
+    type Transform = TokenAuthMiddleware<S>;
+    type Future = Ready<std::result::Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(TokenAuthMiddleware {
+            service,
+            tokens: self.tokens.clone(),
+        })
+    }
+}
+
+pub struct TokenAuthMiddleware<S> {
+    service: S,
+    tokens: Arc<TokenStore>,
+}
+
+enum Denied {
+    Unauthorized(String),
+    Forbidden(String),
+}
+
+fn required_permission(route: &str, method: &Method) -> Permission {
+    if ADMIN_ROUTES.contains(&route) {
+        Permission::Admin
+    } else if method == Method::GET || method == Method::HEAD {
+        Permission::ReadOnly
+    } else {
+        Permission::ReadWrite
+    }
+}
+
+// Library touched by request, from routes like `{lib}/{media}/thumbnail` or param `library`.
+// Routes of a library only serve the one in path, so a different param is refused.
+fn requested_library(route: &str, params: &QString) -> std::result::Result<Option<Uuid>, String> {
+    let path = route.split('/').next().and_then(|v| v.parse::<Uuid>().ok());
+    let param = match params.get("library") {
+        Some(v) => Some(v.parse::<Uuid>().map_err(|e| {
+            format!(
+                "Parameter `library` is not a valid Uuid identifier. Err: {}",
+                e
+            )
+        })?),
+        None => None,
+    };
+    match (path, param) {
+        (Some(path), Some(param)) if path != param => Err(format!(
+            "Parameter `library` `{}` does not match library `{}` in path.",
+            param, path
+        )),
+        (Some(path), _) => Ok(Some(path)),
+        (None, param) => Ok(param),
+    }
+}
+
+impl<S> TokenAuthMiddleware<S> {
+    fn check(&self, req: &ServiceRequest) -> std::result::Result<(), Denied> {
+        let route = req
+            .match_info()
+            .unprocessed()
+            .trim_start_matches('/')
+            .to_string();
+        if PUBLIC_ROUTES.contains(&route.as_str()) {
+            return Ok(());
+        }
+        let params = QString::from(req.query_string());
+        let token = match req.headers().get(header::AUTHORIZATION) {
+            Some(v) => v
+                .to_str()
+                .ok()
+                .and_then(|v| v.strip_prefix("Bearer "))
+                .map(|v| v.trim().to_string()),
+            None if QUERY_TOKEN_ROUTES.contains(&route.as_str()) => {
+                params.get("access_token").map(|v| v.to_string())
+            }
+            None => None,
+        }
+        .ok_or_else(|| Denied::Unauthorized("Bearer token is not provided.".into()))?;
+        let token = self
+            .tokens
+            .get(&token)
+            .ok_or_else(|| Denied::Unauthorized("Bearer token is invalid.".into()))?;
+
+        let required = required_permission(&route, req.method());
+        if token.permission < required {
+            return Err(Denied::Forbidden(format!(
+                "Api `{}` requires permission {:?} but token only has {:?}.",
+                route, required, token.permission
+            )));
+        }
+        if let Some(libraries) = &token.libraries {
+            match requested_library(&route, &params).map_err(Denied::Forbidden)? {
+                Some(library) if !libraries.contains(&library) => {
+                    return Err(Denied::Forbidden(format!(
+                        "Token is not allowed to access library `{}`.",
+                        library
+                    )))
+                }
+                // admin operations on paths are not bound to a library
+                None if required == Permission::Admin
+                    || ALL_LIBRARY_ROUTES.contains(&route.as_str()) =>
+                {
+                    return Err(Denied::Forbidden(format!(
+                        "Token restricted to libraries cannot access api `{}`.",
+                        route
+                    )))
+                }
+                _ => {}
+            }
+        }
+        req.extensions_mut()
+            .insert(TokenLibraries(token.libraries.clone()));
+        Ok(())
+    }
+}
+
+impl<S> Service for TokenAuthMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse, Error = actix_web::Error>,
+    S::Future: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse;
+    type Error = actix_web::Error;
+    type Future = Either<S::Future, Ready<std::result::Result<Self::Response, Self::Error>>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<std::result::Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: ServiceRequest) -> Self::Future {
+        let denied = match self.check(&req) {
+            Ok(_) => return Either::Left(self.service.call(req)),
+            Err(v) => v,
+        };
+        let route = req.match_info().unprocessed().trim_start_matches('/');
+        info!(
+            "Denied request to api {} from {:?}.",
+            route,
+            req.peer_addr()
+        );
+        let (mut resp, detail, code) = match denied {
+            Denied::Unauthorized(v) => {
+                let mut resp = HttpResponse::Unauthorized();
+                resp.header(header::WWW_AUTHENTICATE, "Bearer");
+                (resp, v, ErrorCode::Unauthorized)
+            }
+            Denied::Forbidden(v) => (HttpResponse::Forbidden(), v, ErrorCode::Forbidden),
+        };
+        let msg = ServerMessage {
+            api: route.to_string(),
+            ..ServerMessage::default()
+        }
+        .with_single_error("auth", detail, None, None)
+        .with_error_code(code, None);
+        Either::Right(ok(req.into_response(resp.body(msg.to_json_string()))))
+    }
+}
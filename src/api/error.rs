@@ -1,5 +1,55 @@
+use actix_web::http::StatusCode;
+use serde::{Deserialize, Serialize};
 use shiromana_rs::{library::Library, misc::Error as LibError, misc::Uuid};
 
+// Stable code of error for clients, sent as `code` of message
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum ErrorCode {
+    LibraryNotOpened,
+    MediaNotFound,
+    NotFound,
+    ParamMissing,
+    ParamInvalid,
+    PathNotAllowed,
+    Conflict,
+    PayloadTooLarge,
+    ServerBusy,
+    Unauthorized,
+    Forbidden,
+    Internal,
+}
+
+impl ErrorCode {
+    pub const ALL: [ErrorCode; 12] = [
+        Self::LibraryNotOpened,
+        Self::MediaNotFound,
+        Self::NotFound,
+        Self::ParamMissing,
+        Self::ParamInvalid,
+        Self::PathNotAllowed,
+        Self::Conflict,
+        Self::PayloadTooLarge,
+        Self::ServerBusy,
+        Self::Unauthorized,
+        Self::Forbidden,
+        Self::Internal,
+    ];
+
+    pub fn status(&self) -> StatusCode {
+        match self {
+            Self::LibraryNotOpened | Self::MediaNotFound | Self::NotFound => StatusCode::NOT_FOUND,
+            Self::ParamMissing | Self::ParamInvalid => StatusCode::UNPROCESSABLE_ENTITY,
+            Self::PathNotAllowed | Self::Forbidden => StatusCode::FORBIDDEN,
+            Self::Conflict => StatusCode::CONFLICT,
+            Self::PayloadTooLarge => StatusCode::PAYLOAD_TOO_LARGE,
+            Self::ServerBusy => StatusCode::SERVICE_UNAVAILABLE,
+            Self::Unauthorized => StatusCode::UNAUTHORIZED,
+            Self::Internal => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
 pub enum Error {
     NotExisted {
         got: String,
@@ -7,6 +57,7 @@ pub enum Error {
         expect: String,
     },
     LibraryNotOpened(Uuid),
+    MediaNotFound(u64),
     NoParam(String),
     ParamInvalid {
         got: String,
@@ -21,6 +72,18 @@ pub enum Error {
     IOError(std::io::Error),
     SerializeError(serde_json::Error),
     MultithreadError(Box<dyn std::error::Error + Sync + Send>),
+    UploadError(String),
+    PayloadTooLarge(u64),
+    OffsetMismatch {
+        got: u64,
+        expect: u64,
+    },
+    PathNotAllowed {
+        got: String,
+        field: String,
+    },
+    ServerBusy(usize),
+    Forbidden(String),
 }
 
 impl std::fmt::Display for Error {
@@ -37,6 +100,7 @@ impl std::fmt::Display for Error {
                 field, got
             ),
             Self::LibraryNotOpened(lib) => write!(f, "Library `{}` is not opened.", lib),
+            Self::MediaNotFound(id) => write!(f, "Media `{}` is not found.", id),
             Self::NoParam(what) => write!(f, "Params {} not provided.", what),
             Self::ParamInvalid { got, field, expect } => write!(
                 f,
@@ -46,13 +110,86 @@ impl std::fmt::Display for Error {
             Self::LibraryError(err) => write!(f, "Library Error: {}", err),
             Self::IOError(err) => write!(f, "IO Error: {}", err),
             Self::SerializeError(err) => write!(f, "Serialize Error: {}", err),
-            Self::MultithreadError(err) => write!(f, "Multithrad Error: {}", err)
+            Self::MultithreadError(err) => write!(f, "Multithrad Error: {}", err),
+            Self::UploadError(err) => write!(f, "Upload Error: {}", err),
+            Self::PayloadTooLarge(limit) => {
+                write!(f, "Payload is larger than the limit of {} bytes.", limit)
+            }
+            Self::OffsetMismatch { got, expect } => write!(
+                f,
+                "Upload offset `{}` does not match the offset `{}` of server.",
+                got, expect
+            ),
+            Self::PathNotAllowed { got, field } => write!(
+                f,
+                "Field {}: `{}` is outside of the allowed folders of server.",
+                field, got
+            ),
+            Self::ServerBusy(limit) => write!(
+                f,
+                "Server is busy, {} library operations are already pending.",
+                limit
+            ),
+            Self::Forbidden(detail) => write!(f, "{}", detail),
         }
     }
 }
 
 pub type Result<T> = std::result::Result<T, Error>;
 
+impl Error {
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            Self::NotExisted { .. } => ErrorCode::NotFound,
+            Self::LibraryNotOpened(_) => ErrorCode::LibraryNotOpened,
+            Self::MediaNotFound(_) => ErrorCode::MediaNotFound,
+            Self::NoParam(_) => ErrorCode::ParamMissing,
+            Self::ParamInvalid { .. } | Self::UploadError(_) => ErrorCode::ParamInvalid,
+            Self::AlreadyExisted { .. } | Self::OffsetMismatch { .. } => ErrorCode::Conflict,
+            Self::LibraryError(err) => match err {
+                LibError::NotExists(_) => ErrorCode::NotFound,
+                LibError::AlreadyExists(_) => ErrorCode::Conflict,
+                LibError::TypeMismatch { .. } => ErrorCode::ParamInvalid,
+                _ => ErrorCode::Internal,
+            },
+            Self::IOError(_) | Self::SerializeError(_) | Self::MultithreadError(_) => {
+                ErrorCode::Internal
+            }
+            Self::PayloadTooLarge(_) => ErrorCode::PayloadTooLarge,
+            Self::PathNotAllowed { .. } => ErrorCode::PathNotAllowed,
+            Self::ServerBusy(_) => ErrorCode::ServerBusy,
+            Self::Forbidden(_) => ErrorCode::Forbidden,
+        }
+    }
+
+    // Param which caused the error
+    pub fn field(&self) -> Option<&str> {
+        match self {
+            Self::NotExisted { field, .. }
+            | Self::ParamInvalid { field, .. }
+            | Self::AlreadyExisted { field, .. }
+            | Self::PathNotAllowed { field, .. } => Some(field),
+            Self::NoParam(field) => Some(field),
+            Self::LibraryNotOpened(_) => Some("library"),
+            _ => None,
+        }
+    }
+}
+
+// Library reports missing media as `NotExists`, which is `MediaNotFound` for known id
+pub trait MediaResult<T> {
+    fn for_media(self, id: u64) -> Result<T>;
+}
+
+impl<T> MediaResult<T> for std::result::Result<T, LibError> {
+    fn for_media(self, id: u64) -> Result<T> {
+        self.map_err(|err| match err {
+            LibError::NotExists(_) => Error::MediaNotFound(id),
+            err => Error::LibraryError(err),
+        })
+    }
+}
+
 impl From<LibError> for Error {
     fn from(err: LibError) -> Self {
         Self::LibraryError(err)
@@ -75,4 +212,9 @@ impl From<std::sync::mpsc::RecvError> for Error {
     fn from(err: std::sync::mpsc::RecvError) -> Self {
         Self::MultithreadError(Box::new(err))
     }
-}
\ No newline at end of file
+}
+impl From<actix_multipart::MultipartError> for Error {
+    fn from(err: actix_multipart::MultipartError) -> Self {
+        Self::UploadError(err.to_string())
+    }
+}
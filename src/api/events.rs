@@ -0,0 +1,132 @@
+use serde::Serialize;
+use shiromana_rs::misc::Uuid;
+use tokio::sync::broadcast;
+
+// Events kept for slow listeners before they start missing some
+const EVENT_BUFFER: usize = 256;
+
+// Value of field `type` of each `Event`
+pub const EVENT_TYPES: [&str; 14] = [
+    "library_opened",
+    "library_closed",
+    "media_added",
+    "media_updated",
+    "media_removed",
+    "tag_created",
+    "tag_deleted",
+    "tag_media_added",
+    "tag_media_removed",
+    "series_created",
+    "series_deleted",
+    "series_media_added",
+    "series_media_removed",
+    "series_reordered",
+];
+
+// Change of data, pushed to listeners of `events`
+#[derive(Serialize, Clone, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Event {
+    LibraryOpened {
+        library: Uuid,
+    },
+    LibraryClosed {
+        library: Uuid,
+    },
+    MediaAdded {
+        library: Uuid,
+        media: u64,
+    },
+    MediaUpdated {
+        library: Uuid,
+        media: u64,
+    },
+    MediaRemoved {
+        library: Uuid,
+        media: u64,
+    },
+    TagCreated {
+        library: Uuid,
+        tag: Uuid,
+    },
+    TagDeleted {
+        library: Uuid,
+        tag: Uuid,
+    },
+    TagMediaAdded {
+        library: Uuid,
+        tag: Uuid,
+        media: Vec<u64>,
+    },
+    TagMediaRemoved {
+        library: Uuid,
+        tag: Uuid,
+        media: Vec<u64>,
+    },
+    SeriesCreated {
+        library: Uuid,
+        series: Uuid,
+    },
+    SeriesDeleted {
+        library: Uuid,
+        series: Uuid,
+    },
+    SeriesMediaAdded {
+        library: Uuid,
+        series: Uuid,
+        media: Vec<u64>,
+    },
+    SeriesMediaRemoved {
+        library: Uuid,
+        series: Uuid,
+        media: Vec<u64>,
+    },
+    // Numbers of media in series are changed
+    SeriesReordered {
+        library: Uuid,
+        series: Uuid,
+    },
+}
+
+impl Event {
+    pub fn library(&self) -> &Uuid {
+        match self {
+            Self::LibraryOpened { library }
+            | Self::LibraryClosed { library }
+            | Self::MediaAdded { library, .. }
+            | Self::MediaUpdated { library, .. }
+            | Self::MediaRemoved { library, .. }
+            | Self::TagCreated { library, .. }
+            | Self::TagDeleted { library, .. }
+            | Self::TagMediaAdded { library, .. }
+            | Self::TagMediaRemoved { library, .. }
+            | Self::SeriesCreated { library, .. }
+            | Self::SeriesDeleted { library, .. }
+            | Self::SeriesMediaAdded { library, .. }
+            | Self::SeriesMediaRemoved { library, .. }
+            | Self::SeriesReordered { library, .. } => library,
+        }
+    }
+}
+
+pub struct EventHub {
+    sender: broadcast::Sender<Event>,
+}
+
+impl Default for EventHub {
+    fn default() -> Self {
+        let (sender, _) = broadcast::channel(EVENT_BUFFER);
+        Self { sender }
+    }
+}
+
+impl EventHub {
+    // Nobody listening is not an error
+    pub fn publish(&self, event: Event) {
+        self.sender.send(event).ok();
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<Event> {
+        self.sender.subscribe()
+    }
+}
@@ -0,0 +1,344 @@
+use super::error::{Error, Result};
+use crate::config::ImageConfig;
+use image::codecs::jpeg::JpegEncoder;
+use image::codecs::png::PngEncoder;
+use image::codecs::webp::WebPEncoder;
+use image::imageops::FilterType;
+use image::{ColorType, DynamicImage, ImageEncoder, ImageFormat};
+use log::warn;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Fit {
+    // Inside the box, keeping aspect ratio
+    Contain,
+    // Filling the box, keeping aspect ratio and cropping the overflow
+    Cover,
+    // Stretched to the box
+    Fill,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum OutputFormat {
+    Jpeg,
+    Png,
+    // Lossless, so `quality` is refused with it like png
+    WebP,
+}
+
+impl OutputFormat {
+    fn name(&self) -> &'static str {
+        match self {
+            Self::Jpeg => "jpeg",
+            Self::Png => "png",
+            Self::WebP => "webp",
+        }
+    }
+
+    // Formats which could not be written are converted to png
+    fn of(format: Option<ImageFormat>) -> Self {
+        match format {
+            Some(ImageFormat::Jpeg) => Self::Jpeg,
+            Some(ImageFormat::WebP) => Self::WebP,
+            _ => Self::Png,
+        }
+    }
+
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            Self::Jpeg => "image/jpeg",
+            Self::Png => "image/png",
+            Self::WebP => "image/webp",
+        }
+    }
+}
+
+fn invalid(got: impl ToString, field: &str, expect: &str) -> Error {
+    Error::ParamInvalid {
+        got: got.to_string(),
+        field: field.into(),
+        expect: expect.into(),
+    }
+}
+
+// Params of `{lib}/{media}/image`, validated against config
+pub struct ImageParams {
+    width: Option<u32>,
+    height: Option<u32>,
+    fit: Fit,
+    // Same as original if not given
+    format: Option<OutputFormat>,
+    quality: u8,
+    max_dimension: u32,
+}
+
+impl ImageParams {
+    pub fn new(
+        config: &ImageConfig,
+        width: Option<u32>,
+        height: Option<u32>,
+        fit: Option<String>,
+        format: Option<String>,
+        quality: Option<u32>,
+    ) -> Result<Self> {
+        let max = config.max_dimension;
+        let expect = format!("size between 1 and {}", max);
+        if let Some(w) = width.filter(|v| *v == 0 || *v > max) {
+            return Err(invalid(w, "w", &expect));
+        }
+        if let Some(h) = height.filter(|v| *v == 0 || *v > max) {
+            return Err(invalid(h, "h", &expect));
+        }
+        let fit = match fit.as_deref() {
+            None | Some("contain") => Fit::Contain,
+            Some("cover") => Fit::Cover,
+            Some("fill") => Fit::Fill,
+            Some(v) => return Err(invalid(v, "fit", "one of contain, cover, fill")),
+        };
+        let format = match format.as_deref() {
+            None => None,
+            Some("jpeg") | Some("jpg") => Some(OutputFormat::Jpeg),
+            Some("png") => Some(OutputFormat::Png),
+            Some("webp") => Some(OutputFormat::WebP),
+            Some(v) => return Err(invalid(v, "format", "one of webp, jpeg, png")),
+        };
+        let quality = match quality {
+            Some(v) if !(1..=100).contains(&v) => {
+                return Err(invalid(v, "quality", "quality between 1 and 100"))
+            }
+            // only jpeg is lossy, it would be ignored silently otherwise
+            Some(v) if matches!(format, Some(OutputFormat::Png) | Some(OutputFormat::WebP)) => {
+                return Err(invalid(v, "quality", "quality only with jpeg format"))
+            }
+            Some(v) => v as u8,
+            None => config.quality,
+        };
+        Ok(Self {
+            width,
+            height,
+            fit,
+            format,
+            quality,
+            max_dimension: max,
+        })
+    }
+
+    // Key of resized image in cache, changed once media is replaced
+    pub fn key(&self, media_hash: &str) -> String {
+        let fit = match self.fit {
+            Fit::Contain => "contain",
+            Fit::Cover => "cover",
+            Fit::Fill => "fill",
+        };
+        let params = format!(
+            "{}:{:?}:{:?}:{}:{}:{}:{}",
+            media_hash,
+            self.width,
+            self.height,
+            fit,
+            self.format.map_or("auto", |v| v.name()),
+            self.quality,
+            self.max_dimension
+        );
+        Sha256::digest(params.as_bytes())
+            .iter()
+            .map(|v| format!("{:02x}", v))
+            .collect()
+    }
+
+    fn resize(&self, image: DynamicImage) -> DynamicImage {
+        let max = self.max_dimension;
+        let filter = FilterType::CatmullRom;
+        match (self.width, self.height) {
+            (None, None) if image.width() <= max && image.height() <= max => image,
+            (None, None) => image.resize(max, max, filter),
+            (Some(w), None) => image.resize(w, max, filter),
+            (None, Some(h)) => image.resize(max, h, filter),
+            (Some(w), Some(h)) => match self.fit {
+                Fit::Contain => image.resize(w, h, filter),
+                Fit::Cover => image.resize_to_fill(w, h, filter),
+                Fit::Fill => image.resize_exact(w, h, filter),
+            },
+        }
+    }
+
+    // Decode, resize and encode image at `path`, it is blocking
+    pub fn render<P: AsRef<Path>>(&self, path: P) -> Result<(Vec<u8>, OutputFormat)> {
+        let reader = image::io::Reader::open(path)?.with_guessed_format()?;
+        let format = self
+            .format
+            .unwrap_or_else(|| OutputFormat::of(reader.format()));
+        let image = reader
+            .decode()
+            .map_err(|e| invalid(e, "media", "image which could be decoded"))?;
+        let image = self.resize(image);
+        let (width, height) = (image.width(), image.height());
+        let mut buffer = vec![];
+        let result = match format {
+            OutputFormat::Jpeg => JpegEncoder::new_with_quality(&mut buffer, self.quality)
+                .write_image(&image.to_rgb8(), width, height, ColorType::Rgb8),
+            OutputFormat::Png => PngEncoder::new(&mut buffer).write_image(
+                &image.to_rgba8(),
+                width,
+                height,
+                ColorType::Rgba8,
+            ),
+            OutputFormat::WebP => WebPEncoder::new_lossless(&mut buffer).write_image(
+                &image.to_rgba8(),
+                width,
+                height,
+                ColorType::Rgba8,
+            ),
+        };
+        result.map_err(|e| Error::IOError(std::io::Error::new(std::io::ErrorKind::Other, e)))?;
+        Ok((buffer, format))
+    }
+}
+
+struct CacheEntry {
+    size: u64,
+    // Larger is used more recently
+    used: u64,
+}
+
+struct CacheIndex {
+    entries: HashMap<String, CacheEntry>,
+    total: u64,
+    clock: u64,
+}
+
+// Resized images stored on disk as `{key}.{format}`. Least recently used ones
+// are removed once they take more than `max_size` bytes.
+pub struct ImageCache {
+    dir: PathBuf,
+    max_size: u64,
+    index: Mutex<CacheIndex>,
+}
+
+impl ImageCache {
+    // Images already in `dir` are kept, used order is guessed from modified time
+    pub fn new<P: Into<PathBuf>>(dir: P, max_size: u64) -> Result<Self> {
+        let dir = dir.into();
+        std::fs::create_dir_all(&dir)?;
+        let mut found = vec![];
+        for entry in std::fs::read_dir(&dir)? {
+            let entry = entry?;
+            let meta = entry.metadata()?;
+            let name = entry.file_name().to_string_lossy().to_string();
+            if !meta.is_file() {
+                continue;
+            }
+            // left by writes interrupted by a restart
+            if name.ends_with(".tmp") {
+                std::fs::remove_file(entry.path()).ok();
+                continue;
+            }
+            found.push((meta.modified().ok(), name, meta.len()));
+        }
+        found.sort();
+        let mut index = CacheIndex {
+            entries: HashMap::new(),
+            total: 0,
+            clock: 0,
+        };
+        for (_, name, size) in found {
+            index.clock += 1;
+            index.total += size;
+            let entry = CacheEntry {
+                size,
+                used: index.clock,
+            };
+            index.entries.insert(name, entry);
+        }
+        let cache = Self {
+            dir,
+            max_size,
+            index: Mutex::new(index),
+        };
+        cache.evict(&mut cache.index.lock().unwrap());
+        Ok(cache)
+    }
+
+    pub fn len(&self) -> usize {
+        self.index.lock().unwrap().entries.len()
+    }
+
+    fn name(key: &str, format: OutputFormat) -> String {
+        format!("{}.{}", key, format.name())
+    }
+
+    // Returns image and its format if cached
+    pub fn get(&self, key: &str) -> Option<(Vec<u8>, OutputFormat)> {
+        let (name, format) = {
+            let index = self.index.lock().unwrap();
+            [OutputFormat::Jpeg, OutputFormat::Png, OutputFormat::WebP]
+                .iter()
+                .map(|v| (Self::name(key, *v), *v))
+                .find(|(name, _)| index.entries.contains_key(name))?
+        };
+        match std::fs::read(self.dir.join(&name)) {
+            Ok(v) => {
+                let mut index = self.index.lock().unwrap();
+                index.clock += 1;
+                let clock = index.clock;
+                if let Some(entry) = index.entries.get_mut(&name) {
+                    entry.used = clock;
+                }
+                Some((v, format))
+            }
+            // removed by others, forget it
+            Err(_) => {
+                let mut index = self.index.lock().unwrap();
+                if let Some(entry) = index.entries.remove(&name) {
+                    index.total -= entry.size;
+                }
+                None
+            }
+        }
+    }
+
+    pub fn put(&self, key: &str, format: OutputFormat, content: &[u8]) -> Result<()> {
+        if content.len() as u64 > self.max_size {
+            return Ok(());
+        }
+        let name = Self::name(key, format);
+        let tmp = self.dir.join(format!("{}.tmp", name));
+        std::fs::write(&tmp, content)?;
+        std::fs::rename(tmp, self.dir.join(&name))?;
+        let mut index = self.index.lock().unwrap();
+        index.clock += 1;
+        let entry = CacheEntry {
+            size: content.len() as u64,
+            used: index.clock,
+        };
+        index.total += entry.size;
+        if let Some(old) = index.entries.insert(name, entry) {
+            index.total -= old.size;
+        }
+        self.evict(&mut index);
+        Ok(())
+    }
+
+    fn evict(&self, index: &mut CacheIndex) {
+        while index.total > self.max_size {
+            let oldest = index
+                .entries
+                .iter()
+                .min_by_key(|(_, v)| v.used)
+                .map(|(k, _)| k.clone());
+            let name = match oldest {
+                Some(v) => v,
+                None => break,
+            };
+            if let Some(entry) = index.entries.remove(&name) {
+                index.total -= entry.size;
+            }
+            if let Err(e) = std::fs::remove_file(self.dir.join(&name)) {
+                warn!("Cannot remove cached image {}: {}", name, e);
+            }
+        }
+    }
+}
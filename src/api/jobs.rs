@@ -0,0 +1,494 @@
+use super::error::{Error, ErrorCode, Result};
+use super::libraries::block;
+use crate::config::JobConfig;
+use futures::future::{self, Either, Future, FutureExt};
+use log::warn;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use shiromana_rs::misc::Uuid;
+use std::collections::{BTreeMap, HashMap};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::sync::{Notify, Semaphore};
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|v| v.as_secs())
+        .unwrap_or(0)
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum JobState {
+    // Waiting for other jobs of same library to finish
+    Queued,
+    Running,
+    Succeeded,
+    Failed,
+    Canceled,
+}
+
+impl JobState {
+    pub fn is_finished(&self) -> bool {
+        matches!(self, Self::Succeeded | Self::Failed | Self::Canceled)
+    }
+}
+
+impl FromStr for JobState {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        serde_json::from_value(Value::String(s.to_string())).map_err(|_| Error::ParamInvalid {
+            got: s.to_string(),
+            field: "state".into(),
+            expect: "one of queued, running, succeeded, failed, canceled".into(),
+        })
+    }
+}
+
+#[derive(Serialize, Clone, Default)]
+pub struct JobProgress {
+    pub done: u64,
+    pub total: u64,
+}
+
+#[derive(Serialize, Clone)]
+pub struct JobInfo {
+    pub id: u64,
+    pub library: Uuid,
+    // Operation run by job, like `make_thumbnail`
+    pub kind: String,
+    pub state: JobState,
+    pub progress: JobProgress,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub result: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub code: Option<ErrorCode>,
+    pub created_at: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub started_at: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub finished_at: Option<u64>,
+}
+
+struct Job {
+    info: Mutex<JobInfo>,
+    // What job works on, checkpoint is only resumed by a job of same params
+    params: Value,
+    canceled: AtomicBool,
+    cancel: Notify,
+}
+
+impl Job {
+    fn info(&self) -> JobInfo {
+        self.info.lock().unwrap().clone()
+    }
+
+    // Returns false if job is already finished
+    fn finish(&self, state: JobState, result: Option<Result<Value>>) -> bool {
+        let mut info = self.info.lock().unwrap();
+        if info.state.is_finished() {
+            return false;
+        }
+        info.state = state;
+        info.finished_at = Some(now());
+        match result {
+            Some(Ok(v)) => info.result = Some(v),
+            Some(Err(e)) => {
+                info.code = Some(e.code());
+                info.error = Some(e.to_string());
+            }
+            None => {}
+        }
+        true
+    }
+}
+
+// Handed to body of job to report progress and check cancellation
+#[derive(Clone)]
+pub struct JobContext {
+    job: Arc<Job>,
+    manager: Arc<JobManager>,
+}
+
+impl JobContext {
+    pub fn id(&self) -> u64 {
+        self.job.info.lock().unwrap().id
+    }
+
+    pub fn progress(&self, done: u64, total: u64) {
+        self.job.info.lock().unwrap().progress = JobProgress { done, total };
+    }
+
+    // Result so far, kept if job is canceled or fails later
+    pub fn report(&self, result: Value) {
+        self.job.info.lock().unwrap().result = Some(result);
+    }
+
+    // Long jobs should stop at their next step once canceled
+    pub fn is_canceled(&self) -> bool {
+        self.job.canceled.load(Ordering::SeqCst)
+    }
+
+    // Where the job is, saved to disk until a job of same kind, library and params succeeds
+    pub async fn checkpoint(&self, value: Value) -> Result<()> {
+        let (library, kind) = {
+            let info = self.job.info.lock().unwrap();
+            (info.library, info.kind.clone())
+        };
+        let checkpoint = Checkpoint {
+            params: self.job.params.clone(),
+            value,
+        };
+        self.manager
+            .checkpoints
+            .lock()
+            .unwrap()
+            .entry(library)
+            .or_default()
+            .insert(kind, checkpoint);
+        self.manager.save_checkpoints().await
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+struct Checkpoint {
+    params: Value,
+    value: Value,
+}
+
+// Last checkpoint of jobs by library and kind
+type Checkpoints = HashMap<Uuid, HashMap<String, Checkpoint>>;
+
+// Jobs run in background on the arbiter submitting them. At most `concurrency`
+// jobs of a library run at the same time, others are queued in order.
+// Finished jobs are kept in memory until `history` newer ones are finished,
+// only checkpoints of jobs are saved into `file` of config.
+pub struct JobManager {
+    config: JobConfig,
+    jobs: Mutex<BTreeMap<u64, Arc<Job>>>,
+    limits: Mutex<HashMap<Uuid, Arc<Semaphore>>>,
+    next_id: AtomicU64,
+    checkpoints: Mutex<Checkpoints>,
+}
+
+impl JobManager {
+    // Load checkpoints from `file` of config, which is created once needed
+    pub fn load(config: &JobConfig) -> Result<Self> {
+        let checkpoints = if config.file.exists() {
+            serde_json::from_slice(&std::fs::read(&config.file)?)?
+        } else {
+            HashMap::new()
+        };
+        Ok(Self {
+            config: config.clone(),
+            jobs: Mutex::new(BTreeMap::new()),
+            limits: Mutex::new(HashMap::new()),
+            next_id: AtomicU64::new(1),
+            checkpoints: Mutex::new(checkpoints),
+        })
+    }
+
+    // Checkpoint left by a job of library, kind and params
+    pub fn checkpoint(&self, library: &Uuid, kind: &str, params: &Value) -> Option<Value> {
+        self.checkpoints
+            .lock()
+            .unwrap()
+            .get(library)
+            .and_then(|v| v.get(kind))
+            .filter(|v| &v.params == params)
+            .map(|v| v.value.clone())
+    }
+
+    async fn save_checkpoints(self: &Arc<Self>) -> Result<()> {
+        let manager = self.clone();
+        block(move || {
+            // locked through writing, so saves never interleave
+            let checkpoints = manager.checkpoints.lock().unwrap();
+            let file = &manager.config.file;
+            let tmp = file.with_extension("json.tmp");
+            std::fs::write(&tmp, serde_json::to_vec_pretty(&*checkpoints)?)?;
+            std::fs::rename(tmp, file)?;
+            Ok(())
+        })
+        .await
+    }
+
+    // Job went through, so there is nothing left to resume for its params,
+    // while checkpoint of other params is kept
+    async fn clear_checkpoint(self: &Arc<Self>, library: &Uuid, kind: &str, params: &Value) {
+        let removed = match self.checkpoints.lock().unwrap().get_mut(library) {
+            Some(v) if v.get(kind).map_or(false, |v| &v.params == params) => {
+                v.remove(kind).is_some()
+            }
+            _ => false,
+        };
+        if !removed {
+            return;
+        }
+        if let Err(e) = self.save_checkpoints().await {
+            warn!("Cannot save checkpoints of jobs: {}", e);
+        }
+    }
+
+    fn limit(&self, library: &Uuid) -> Arc<Semaphore> {
+        self.limits
+            .lock()
+            .unwrap()
+            .entry(*library)
+            .or_insert_with(|| Arc::new(Semaphore::new(self.config.concurrency.max(1))))
+            .clone()
+    }
+
+    // Queue `body` as a job of library, its result is kept as result of job
+    pub fn submit<F, Fut>(
+        self: &Arc<Self>,
+        library: Uuid,
+        kind: &str,
+        params: Value,
+        body: F,
+    ) -> JobInfo
+    where
+        F: FnOnce(JobContext) -> Fut + 'static,
+        Fut: Future<Output = Result<Value>> + 'static,
+    {
+        let info = JobInfo {
+            id: self.next_id.fetch_add(1, Ordering::SeqCst),
+            library,
+            kind: kind.to_string(),
+            state: JobState::Queued,
+            progress: JobProgress::default(),
+            result: None,
+            error: None,
+            code: None,
+            created_at: now(),
+            started_at: None,
+            finished_at: None,
+        };
+        let job = Arc::new(Job {
+            info: Mutex::new(info.clone()),
+            params,
+            canceled: AtomicBool::new(false),
+            cancel: Notify::new(),
+        });
+        self.jobs.lock().unwrap().insert(info.id, job.clone());
+
+        let manager = self.clone();
+        let kind = kind.to_string();
+        actix_web::rt::spawn(async move {
+            let limit = manager.limit(&library);
+            let acquire = limit.acquire_owned().boxed_local();
+            let permit = match future::select(acquire, job.cancel.notified().boxed_local()).await {
+                Either::Left((Ok(permit), _)) => permit,
+                _ => return manager.prune(),
+            };
+            {
+                let mut info = job.info.lock().unwrap();
+                if info.state.is_finished() {
+                    drop(info);
+                    return manager.prune();
+                }
+                info.state = JobState::Running;
+                info.started_at = Some(now());
+            }
+            // never dropped halfway, as blocking work it started goes on anyway,
+            // so permit is held until the body stops on its own
+            let result = body(JobContext {
+                job: job.clone(),
+                manager: manager.clone(),
+            })
+            .await;
+            let state = match (&result, job.canceled.load(Ordering::SeqCst)) {
+                (_, true) => JobState::Canceled,
+                (Ok(_), false) => JobState::Succeeded,
+                (Err(_), false) => JobState::Failed,
+            };
+            if state == JobState::Succeeded {
+                manager.clear_checkpoint(&library, &kind, &job.params).await;
+            }
+            job.finish(state, Some(result));
+            drop(permit);
+            manager.prune();
+        });
+        info
+    }
+
+    // Drop oldest finished jobs beyond history
+    fn prune(&self) {
+        let mut jobs = self.jobs.lock().unwrap();
+        let finished: Vec<u64> = jobs
+            .iter()
+            .filter(|(_, v)| v.info.lock().unwrap().state.is_finished())
+            .map(|(k, _)| *k)
+            .collect();
+        let excess = finished.len().saturating_sub(self.config.history);
+        for id in finished.into_iter().take(excess) {
+            jobs.remove(&id);
+        }
+    }
+
+    fn job(&self, library: &Uuid, id: u64) -> Result<Arc<Job>> {
+        self.jobs
+            .lock()
+            .unwrap()
+            .get(&id)
+            .filter(|v| &v.info.lock().unwrap().library == library)
+            .cloned()
+            .ok_or_else(|| Error::NotExisted {
+                got: id.to_string(),
+                field: "job".into(),
+                expect: format!("job of library {}", library),
+            })
+    }
+
+    pub fn get(&self, library: &Uuid, id: u64) -> Result<JobInfo> {
+        Ok(self.job(library, id)?.info())
+    }
+
+    // Queued job is canceled at once. Running one is asked to stop, which it does
+    // at its next check of `JobContext::is_canceled`, then it is marked canceled.
+    pub fn cancel(&self, library: &Uuid, id: u64) -> Result<JobInfo> {
+        let job = self.job(library, id)?;
+        let mut info = job.info.lock().unwrap();
+        match info.state {
+            JobState::Queued => {
+                info.state = JobState::Canceled;
+                info.finished_at = Some(now());
+                job.cancel.notify_one();
+            }
+            JobState::Running => {}
+            _ => {
+                return Err(Error::ParamInvalid {
+                    got: id.to_string(),
+                    field: "job".into(),
+                    expect: "queued or running job".into(),
+                })
+            }
+        }
+        job.canceled.store(true, Ordering::SeqCst);
+        Ok(info.clone())
+    }
+
+    // Newest jobs first
+    pub fn list(&self, library: Option<&Uuid>, state: Option<JobState>) -> Vec<JobInfo> {
+        self.jobs
+            .lock()
+            .unwrap()
+            .values()
+            .rev()
+            .map(|v| v.info())
+            .filter(|v| library.map_or(true, |lib| &v.library == lib))
+            .filter(|v| state.map_or(true, |state| v.state == state))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::libraries::block;
+    use crate::api::testing::TempDir;
+    use std::time::Duration;
+
+    fn create_manager(dir: &TempDir) -> Arc<JobManager> {
+        let config = JobConfig {
+            concurrency: 1,
+            history: 10,
+            file: dir.path().join("jobs.json"),
+        };
+        Arc::new(JobManager::load(&config).unwrap_or_else(|e| panic!("{}", e)))
+    }
+
+    fn state(jobs: &JobManager, library: &Uuid, id: u64) -> JobState {
+        jobs.get(library, id)
+            .unwrap_or_else(|e| panic!("{}", e))
+            .state
+    }
+
+    #[actix_rt::test]
+    async fn canceled_job_holds_permit_until_its_work_returns() {
+        let dir = TempDir::new();
+        let jobs = create_manager(&dir);
+        let library: Uuid = "00000000-0000-0000-0000-000000000001".parse().unwrap();
+        let slow = jobs.submit(library, "slow", Value::Null, |ctx| async move {
+            block(|| {
+                std::thread::sleep(Duration::from_secs(1));
+                Ok(())
+            })
+            .await?;
+            Ok(Value::Bool(ctx.is_canceled()))
+        });
+        let next = jobs.submit(library, "next", Value::Null, |_| async { Ok(Value::Null) });
+        actix_rt::time::delay_for(Duration::from_millis(200)).await;
+        assert!(state(&jobs, &library, slow.id) == JobState::Running);
+
+        let canceled = jobs
+            .cancel(&library, slow.id)
+            .unwrap_or_else(|e| panic!("{}", e));
+        assert!(canceled.state == JobState::Running);
+        actix_rt::time::delay_for(Duration::from_millis(200)).await;
+        // blocking work of canceled job is still running
+        assert!(state(&jobs, &library, next.id) == JobState::Queued);
+
+        actix_rt::time::delay_for(Duration::from_secs(1)).await;
+        let slow = jobs
+            .get(&library, slow.id)
+            .unwrap_or_else(|e| panic!("{}", e));
+        assert!(slow.state == JobState::Canceled);
+        assert!(slow.result == Some(Value::Bool(true)));
+        assert!(state(&jobs, &library, next.id) == JobState::Succeeded);
+    }
+
+    #[actix_rt::test]
+    async fn queued_job_is_canceled_at_once() {
+        let dir = TempDir::new();
+        let jobs = create_manager(&dir);
+        let library: Uuid = "00000000-0000-0000-0000-000000000001".parse().unwrap();
+        jobs.submit(library, "slow", Value::Null, |_| async {
+            actix_rt::time::delay_for(Duration::from_millis(500)).await;
+            Ok(Value::Null)
+        });
+        let queued = jobs.submit(library, "queued", Value::Null, |_| async {
+            Ok(Value::Null)
+        });
+        actix_rt::time::delay_for(Duration::from_millis(100)).await;
+        let canceled = jobs
+            .cancel(&library, queued.id)
+            .unwrap_or_else(|e| panic!("{}", e));
+        assert!(canceled.state == JobState::Canceled);
+        actix_rt::time::delay_for(Duration::from_millis(600)).await;
+        assert!(state(&jobs, &library, queued.id) == JobState::Canceled);
+    }
+
+    #[actix_rt::test]
+    async fn checkpoint_is_kept_until_job_of_same_params_succeeds() {
+        let dir = TempDir::new();
+        let jobs = create_manager(&dir);
+        let library: Uuid = "00000000-0000-0000-0000-000000000001".parse().unwrap();
+        let (all, some) = (Value::from("all"), Value::from("some"));
+        jobs.submit(library, "walk", all.clone(), |ctx| async move {
+            ctx.checkpoint(Value::from(7)).await?;
+            Err(Error::UploadError("interrupted".into()))
+        });
+        actix_rt::time::delay_for(Duration::from_millis(200)).await;
+        // as after a restart
+        let restarted = JobManager::load(&jobs.config).unwrap_or_else(|e| panic!("{}", e));
+        assert!(restarted.checkpoint(&library, "walk", &all) == Some(Value::from(7)));
+        assert!(restarted.checkpoint(&library, "walk", &some).is_none());
+
+        jobs.submit(library, "walk", some, |_| async { Ok(Value::Null) });
+        actix_rt::time::delay_for(Duration::from_millis(200)).await;
+        let restarted = JobManager::load(&jobs.config).unwrap_or_else(|e| panic!("{}", e));
+        assert!(restarted.checkpoint(&library, "walk", &all) == Some(Value::from(7)));
+
+        jobs.submit(library, "walk", all.clone(), |_| async { Ok(Value::Null) });
+        actix_rt::time::delay_for(Duration::from_millis(200)).await;
+        let restarted = JobManager::load(&jobs.config).unwrap_or_else(|e| panic!("{}", e));
+        assert!(restarted.checkpoint(&library, "walk", &all).is_none());
+    }
+}
@@ -0,0 +1,178 @@
+use super::error::{Error, Result};
+use actix_web::web;
+use shiromana_rs::library::Library;
+use shiromana_rs::misc::Uuid;
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock as StdRwLock};
+use tokio::sync::{OwnedSemaphorePermit, RwLock, Semaphore};
+
+pub type LibraryHandle = Arc<RwLock<Library>>;
+
+// Run blocking work like file operations on the blocking thread pool
+pub async fn block<F, T>(f: F) -> Result<T>
+where
+    F: FnOnce() -> Result<T> + Send + 'static,
+    T: Send + 'static,
+{
+    match web::block(move || Ok::<_, ()>(f())).await {
+        Ok(v) => v,
+        Err(_) => Err(Error::MultithreadError("Blocking task is canceled.".into())),
+    }
+}
+
+// Opened libraries, each behind its own lock so a slow operation on one library
+// never blocks others. Map itself is only locked for lookup.
+// Library operations are blocking, they run on the blocking thread pool and at
+// most `max_pending` of them could be queued or running at the same time.
+// Waiting for the lock of a library takes no permit, so a slow library could
+// not use up the permits of others.
+pub struct OpenedLibraries {
+    libraries: StdRwLock<HashMap<Uuid, LibraryHandle>>,
+    pending: Arc<Semaphore>,
+    max_pending: usize,
+}
+
+impl OpenedLibraries {
+    pub fn new(max_pending: usize) -> Self {
+        Self {
+            libraries: StdRwLock::new(HashMap::new()),
+            pending: Arc::new(Semaphore::new(max_pending)),
+            max_pending,
+        }
+    }
+
+    fn permit(&self) -> Result<OwnedSemaphorePermit> {
+        self.pending
+            .clone()
+            .try_acquire_owned()
+            .map_err(|_| Error::ServerBusy(self.max_pending))
+    }
+
+    async fn run<F, T>(permit: OwnedSemaphorePermit, f: F) -> Result<T>
+    where
+        F: FnOnce() -> Result<T> + Send + 'static,
+        T: Send + 'static,
+    {
+        block(move || {
+            let _permit = permit;
+            f()
+        })
+        .await
+    }
+
+    // Run blocking work which does not touch a library, like opening one
+    pub async fn spawn_blocking<F, T>(&self, f: F) -> Result<T>
+    where
+        F: FnOnce() -> Result<T> + Send + 'static,
+        T: Send + 'static,
+    {
+        Self::run(self.permit()?, f).await
+    }
+
+    // Run `f` with shared lock of library `uuid`
+    pub async fn read<F, T>(&self, uuid: &Uuid, f: F) -> Result<T>
+    where
+        F: FnOnce(&Library) -> Result<T> + Send + 'static,
+        T: Send + 'static,
+    {
+        let lib = self.get(uuid)?.read_owned().await;
+        Self::run(self.permit()?, move || f(&lib)).await
+    }
+
+    // Run `f` with exclusive lock of library `uuid`
+    pub async fn write<F, T>(&self, uuid: &Uuid, f: F) -> Result<T>
+    where
+        F: FnOnce(&mut Library) -> Result<T> + Send + 'static,
+        T: Send + 'static,
+    {
+        let mut lib = self.get(uuid)?.write_owned().await;
+        Self::run(self.permit()?, move || f(&mut lib)).await
+    }
+
+    pub fn get(&self, uuid: &Uuid) -> Result<LibraryHandle> {
+        self.libraries
+            .read()
+            .unwrap()
+            .get(uuid)
+            .cloned()
+            .ok_or_else(|| Error::LibraryNotOpened(uuid.clone()))
+    }
+
+    pub fn contains(&self, uuid: &Uuid) -> bool {
+        self.libraries.read().unwrap().contains_key(uuid)
+    }
+
+    // Returns false if a library with same uuid is already opened, which is kept
+    pub fn insert(&self, lib: Library) -> bool {
+        let mut libraries = self.libraries.write().unwrap();
+        if libraries.contains_key(&lib.uuid) {
+            return false;
+        }
+        libraries.insert(lib.uuid.clone(), Arc::new(RwLock::new(lib)));
+        true
+    }
+
+    // Library is closed once the last request holding it finished
+    pub fn remove(&self, uuid: &Uuid) -> Option<LibraryHandle> {
+        self.libraries.write().unwrap().remove(uuid)
+    }
+
+    pub fn handles(&self) -> Vec<(Uuid, LibraryHandle)> {
+        self.libraries
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect()
+    }
+
+    pub fn clear(&self) {
+        self.libraries.write().unwrap().clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::testing::TempDir;
+    use shiromana_rs::library::LibraryFeatures;
+    use std::time::{Duration, Instant};
+
+    fn create_library(dir: &TempDir, name: &str) -> Library {
+        let path = dir.path().join(name);
+        Library::create(
+            path.to_string_lossy().to_string(),
+            name.to_string(),
+            None,
+            None,
+            LibraryFeatures::new(),
+        )
+        .unwrap()
+    }
+
+    #[actix_rt::test]
+    async fn slow_library_does_not_stall_others() {
+        let libs = OpenedLibraries::new(8);
+        let dir = TempDir::new();
+        let (slow, fast) = (create_library(&dir, "slow"), create_library(&dir, "fast"));
+        let (slow_uuid, fast_uuid) = (slow.uuid.clone(), fast.uuid.clone());
+        assert!(libs.insert(slow));
+        assert!(libs.insert(fast));
+
+        let slow = libs.write(&slow_uuid, |_| {
+            std::thread::sleep(Duration::from_secs(2));
+            Ok(())
+        });
+        let fast = async {
+            // let the slow operation take its library first
+            actix_rt::time::delay_for(Duration::from_millis(200)).await;
+            let start = Instant::now();
+            let result = libs.read(&fast_uuid, |lib| Ok(lib.uuid.clone())).await;
+            assert!(result.is_ok());
+            start.elapsed()
+        };
+        let (slow, elapsed) = futures::join!(slow, fast);
+        assert!(slow.is_ok());
+        assert!(elapsed < Duration::from_secs(1), "waited {:?}", elapsed);
+    }
+}
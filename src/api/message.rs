@@ -1,4 +1,4 @@
-use super::error::Result;
+use super::error::{ErrorCode, Result};
 use paste::paste;
 use serde::{Deserialize, Serialize};
 use shiromana_rs::misc::Uuid;
@@ -11,6 +11,23 @@ pub enum ServerApiStatus {
     Failed,
 }
 
+// Media type of v2 messages, also accepted from header `Accept` to choose v2
+pub const V2_MEDIA_TYPE: &str = "application/vnd.shiromana.v2+json";
+
+// Version of message envelope. In v1 `result` is always a string, json results
+// are encoded into it. In v2 `result` is the json itself.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ApiVersion {
+    V1,
+    V2,
+}
+
+impl Default for ApiVersion {
+    fn default() -> Self {
+        Self::V1
+    }
+}
+
 #[derive(Deserialize, Serialize, Clone)]
 pub struct ServerMessage {
     pub api: String,
@@ -18,17 +35,24 @@ pub struct ServerMessage {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub error: Option<Vec<(String, String)>>,
     #[serde(skip_serializing_if = "Option::is_none")]
+    pub code: Option<ErrorCode>,
+    // Param which caused the error
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub field: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub library: Option<Uuid>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub media: Option<u64>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub format: Option<&'static str>,
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub result: Option<String>,
+    pub result: Option<serde_json::Value>,
     #[serde(skip_serializing_if = "HashMap::is_empty")]
     pub data: HashMap<String, String>,
     #[serde(skip_serializing)]
     pub is_preety: bool,
+    #[serde(skip)]
+    pub version: ApiVersion,
 }
 
 impl Default for ServerMessage {
@@ -37,12 +61,15 @@ impl Default for ServerMessage {
             api: "".into(),
             status: ServerApiStatus::Success,
             error: None,
+            code: None,
+            field: None,
             library: None,
             media: None,
             format: None,
             result: None,
             data: HashMap::new(),
             is_preety: true,
+            version: ApiVersion::V1,
         }
     }
 }
@@ -119,12 +146,42 @@ impl ServerMessage {
         }
     }
 
-    pub fn to_json_string(&self) -> String {
-        let possible_result = if self.is_preety {
-            serde_json::to_string_pretty(&self)
-        } else {
-            serde_json::to_string(&self)
+    // Json result encoded into string for v1
+    fn to_v1(&self) -> std::result::Result<Option<Self>, serde_json::Error> {
+        let result = match &self.result {
+            Some(v) if !v.is_string() => match self.is_preety {
+                true => serde_json::to_string_pretty(v)?,
+                false => serde_json::to_string(v)?,
+            },
+            _ => return Ok(None),
         };
+        Ok(Some(Self {
+            result: Some(result.into()),
+            ..self.clone()
+        }))
+    }
+
+    pub fn with_error_code(self, code: ErrorCode, field: Option<&str>) -> Self {
+        Self {
+            code: Some(code),
+            field: field.map(|v| v.to_string()),
+            ..self
+        }
+    }
+
+    pub fn to_json_string(&self) -> String {
+        let possible_result = match self.version {
+            ApiVersion::V1 => self.to_v1(),
+            ApiVersion::V2 => Ok(None),
+        }
+        .and_then(|v1| {
+            let msg = v1.as_ref().unwrap_or(self);
+            if self.is_preety {
+                serde_json::to_string_pretty(msg)
+            } else {
+                serde_json::to_string(msg)
+            }
+        });
         match possible_result {
             Ok(v) => v + "\n",
             Err(e) => format!(r#"{{"api": "server", "status": "Failed", "error": [["server side cannot serialize message json": "{}"]]}}\n"#, e).to_string()
@@ -149,15 +206,20 @@ impl ServerMessage {
     generate_with_function!(data, HashMap<String, String>);
     generate_with_function!(library, Uuid, Some);
     generate_with_function!(media, u64, Some);
-    generate_with_function!(result, String, Some);
     generate_with_function!(format, &'static str, Some);
 
+    generate_with_function!(version, ApiVersion);
+
+    pub fn with_result<F: Into<String>>(self, v: F) -> Self {
+        Self {
+            result: Some(serde_json::Value::String(v.into())),
+            ..self
+        }
+    }
+
     pub fn with_serialized_result<T: serde::Serialize>(self, v: &T) -> Result<Self> {
         Ok(Self {
-            result: Some(match self.is_preety {
-                true => serde_json::to_string_pretty(v)?,
-                false => serde_json::to_string(v)?,
-            }),
+            result: Some(serde_json::to_value(v)?),
             ..self
         })
     }
@@ -1,6 +1,20 @@
+pub mod auth;
 mod error;
+pub mod events;
+pub mod images;
+pub mod jobs;
+pub mod libraries;
 mod message;
+pub mod paths;
+mod query;
+pub mod registry;
 mod routes;
+#[cfg(test)]
+mod testing;
+pub mod upload;
+pub mod webhooks;
+
+pub use message::ApiVersion;
 
 use actix_web::{
     dev::HttpServiceFactory,
@@ -11,5 +25,6 @@ use actix_web::{
 
 pub fn service_config(cfg: &mut web::ServiceConfig) {
     // cfg.service(broker::media_get);
+    cfg.app_data(web::PayloadConfig::new(query::BODY_LIMIT));
     routes::services(cfg);
 }
@@ -0,0 +1,56 @@
+use super::error::{Error, Result};
+use std::path::{Path, PathBuf};
+
+// Directories of server which apis are allowed to touch
+pub struct AllowedRoots {
+    roots: Vec<PathBuf>,
+}
+
+impl AllowedRoots {
+    // Roots must exist so they could be canonicalized. Empty roots allow any path.
+    pub fn new<I: IntoIterator<Item = P>, P: AsRef<Path>>(roots: I) -> Result<Self> {
+        let roots = roots
+            .into_iter()
+            .map(|v| v.as_ref().canonicalize())
+            .collect::<std::io::Result<Vec<_>>>()?;
+        Ok(Self { roots })
+    }
+
+    pub fn is_unrestricted(&self) -> bool {
+        self.roots.is_empty()
+    }
+
+    pub fn roots(&self) -> &[PathBuf] {
+        &self.roots
+    }
+
+    // Resolve symlinks and `..` of `path`. For a path not existed yet, its parent is resolved.
+    fn canonicalize(field: &str, path: &Path) -> Result<PathBuf> {
+        if path.exists() {
+            return Ok(path.canonicalize()?);
+        }
+        let not_allowed = || Error::PathNotAllowed {
+            got: path.to_string_lossy().to_string(),
+            field: field.to_string(),
+        };
+        let name = path.file_name().ok_or_else(not_allowed)?;
+        let parent = match path.parent() {
+            Some(v) if !v.as_os_str().is_empty() => v,
+            _ => Path::new("."),
+        };
+        Ok(parent.canonicalize().map_err(|_| not_allowed())?.join(name))
+    }
+
+    // Canonicalize param `field` and ensure it is under one of roots
+    pub fn check<P: AsRef<Path>>(&self, field: &str, path: P) -> Result<PathBuf> {
+        let path = Self::canonicalize(field, path.as_ref())?;
+        if self.roots.is_empty() || self.roots.iter().any(|root| path.starts_with(root)) {
+            Ok(path)
+        } else {
+            Err(Error::PathNotAllowed {
+                got: path.to_string_lossy().to_string(),
+                field: field.to_string(),
+            })
+        }
+    }
+}
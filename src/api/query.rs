@@ -0,0 +1,325 @@
+use super::error::{Error, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use shiromana_rs::library::Library;
+use std::cmp::Ordering;
+use std::convert::TryFrom;
+use std::str::FromStr;
+
+// Medias are loaded from library in batches of this size
+pub const QUERY_BATCH: usize = 512;
+
+// Ranges of ids are expanded, so they are limited
+pub const MEDIA_IDS_LIMIT: usize = 100_000;
+
+// Request bodies could carry a json array of `MEDIA_IDS_LIMIT` ids beside other params
+pub const BODY_LIMIT: usize = MEDIA_IDS_LIMIT * 24 + 64 * 1024;
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum MediaSort {
+    Id,
+    AddTime,
+    Caption,
+    Size,
+}
+
+impl MediaSort {
+    pub const ALL: [MediaSort; 4] = [Self::Id, Self::AddTime, Self::Caption, Self::Size];
+
+    // Field of serialized `Media` to sort by
+    fn key(&self) -> &'static str {
+        match self {
+            Self::Id => "id",
+            Self::AddTime => "time_add",
+            Self::Caption => "caption",
+            Self::Size => "file_size",
+        }
+    }
+}
+
+impl FromStr for MediaSort {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        serde_json::from_value(Value::String(s.to_string())).map_err(|_| Error::ParamInvalid {
+            got: s.to_string(),
+            field: "sort".into(),
+            expect: "one of id, add_time, caption, size".into(),
+        })
+    }
+}
+
+// Media ids like `3`, `1,4,9` or `10-20,25`, ranges are inclusive
+#[derive(Deserialize)]
+#[serde(try_from = "Value")]
+pub struct MediaIds {
+    pub ids: Vec<u64>,
+    // Only one id is given like before, without list or range
+    pub single: bool,
+}
+
+impl MediaIds {
+    fn invalid(got: &str) -> Error {
+        Error::ParamInvalid {
+            got: got.to_string(),
+            field: "media".into(),
+            expect: format!(
+                "media id, or list and ranges of ids like `1,4-9` within {} ids",
+                MEDIA_IDS_LIMIT
+            ),
+        }
+    }
+
+    fn from_ids(ids: Vec<u64>, single: bool) -> Result<Self> {
+        match ids.len() > MEDIA_IDS_LIMIT {
+            true => Err(Self::invalid(&format!("{} ids", ids.len()))),
+            false => Ok(Self { ids, single }),
+        }
+    }
+}
+
+impl FromStr for MediaIds {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut ids = vec![];
+        for part in s.split(',').map(str::trim).filter(|v| !v.is_empty()) {
+            let parse = |v: &str| v.trim().parse::<u64>().map_err(|_| Self::invalid(s));
+            let (start, end) = match part.split_once('-') {
+                Some((start, end)) => (parse(start)?, parse(end)?),
+                None => (parse(part)?, parse(part)?),
+            };
+            let count = end
+                .checked_sub(start)
+                .and_then(|v| usize::try_from(v).ok())
+                .and_then(|v| v.checked_add(1));
+            match count {
+                Some(v) if ids.len().saturating_add(v) <= MEDIA_IDS_LIMIT => {
+                    ids.extend(start..=end)
+                }
+                _ => return Err(Self::invalid(s)),
+            }
+        }
+        if ids.is_empty() {
+            return Err(Self::invalid(s));
+        }
+        Self::from_ids(ids, !s.contains(|c| c == ',' || c == '-'))
+    }
+}
+
+impl TryFrom<Value> for MediaIds {
+    type Error = Error;
+
+    fn try_from(value: Value) -> Result<Self> {
+        match value {
+            Value::Number(v) => match v.as_u64() {
+                Some(id) => Self::from_ids(vec![id], true),
+                None => Err(Self::invalid(&v.to_string())),
+            },
+            Value::String(v) => v.parse(),
+            Value::Array(v) => {
+                let ids = v.iter().map(Value::as_u64).collect::<Option<Vec<_>>>();
+                match ids {
+                    Some(ids) if !ids.is_empty() => Self::from_ids(ids, false),
+                    _ => Err(Self::invalid(&Value::Array(v).to_string())),
+                }
+            }
+            v => Err(Self::invalid(&v.to_string())),
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct MediaQueryResult {
+    pub id: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub media: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+// Position in sorted results, handed to client as an opaque token
+#[derive(Serialize, Deserialize)]
+struct Cursor {
+    sort: MediaSort,
+    desc: bool,
+    key: Value,
+    id: u64,
+}
+
+// Order of json values of the same field, values of different kinds are ordered by kind
+fn compare_values(a: &Value, b: &Value) -> Ordering {
+    fn rank(v: &Value) -> u8 {
+        match v {
+            Value::Null => 0,
+            Value::Bool(_) => 1,
+            Value::Number(_) => 2,
+            Value::String(_) => 3,
+            Value::Array(_) => 4,
+            Value::Object(_) => 5,
+        }
+    }
+    match (a, b) {
+        (Value::Bool(a), Value::Bool(b)) => a.cmp(b),
+        (Value::Number(a), Value::Number(b)) => match (a.as_u64(), b.as_u64()) {
+            (Some(a), Some(b)) => a.cmp(&b),
+            _ => a
+                .as_f64()
+                .partial_cmp(&b.as_f64())
+                .unwrap_or(Ordering::Equal),
+        },
+        (Value::String(a), Value::String(b)) => a.cmp(b),
+        (a, b) => rank(a).cmp(&rank(b)),
+    }
+}
+
+#[derive(Clone)]
+pub struct MediaQuery {
+    pub sort: MediaSort,
+    pub desc: bool,
+    // Keys of `Media` to keep, all if not provided
+    pub fields: Option<Vec<String>>,
+}
+
+// Matched ids sorted with the key they are sorted by
+pub struct SortedIds {
+    query: MediaQuery,
+    ids: Vec<(Value, u64)>,
+}
+
+impl MediaQuery {
+    pub fn run(&self, lib: &Library, q: &str) -> Result<SortedIds> {
+        let ids = lib.query_media(q)?;
+        let mut ids = match self.sort {
+            MediaSort::Id => ids.into_iter().map(|id| (Value::from(id), id)).collect(),
+            sort => {
+                // keep only the key so memory does not grow with whole medias
+                let mut keyed = Vec::with_capacity(ids.len());
+                for batch in ids.chunks(QUERY_BATCH) {
+                    for (id, media) in lib.get_medias(batch.iter().cloned()) {
+                        let key = media
+                            .ok()
+                            .and_then(|v| serde_json::to_value(v).ok())
+                            .and_then(|mut v| v.get_mut(sort.key()).map(Value::take))
+                            .unwrap_or(Value::Null);
+                        keyed.push((key, id));
+                    }
+                }
+                keyed
+            }
+        };
+        ids.sort_by(|a, b| compare_values(&a.0, &b.0).then(a.1.cmp(&b.1)));
+        if self.desc {
+            ids.reverse();
+        }
+        Ok(SortedIds {
+            query: self.clone(),
+            ids,
+        })
+    }
+
+    // Load medias of `ids`, with only the selected fields
+    pub fn load(&self, lib: &Library, ids: &[u64]) -> Result<Vec<MediaQueryResult>> {
+        let mut results = Vec::with_capacity(ids.len());
+        for (id, media) in lib.get_medias(ids.iter().cloned()) {
+            results.push(match media {
+                Ok(media) => MediaQueryResult {
+                    id,
+                    media: Some(self.project(serde_json::to_value(media)?)?),
+                    error: None,
+                },
+                Err(e) => MediaQueryResult {
+                    id,
+                    media: None,
+                    error: Some(e.to_string()),
+                },
+            });
+        }
+        Ok(results)
+    }
+
+    fn project(&self, media: Value) -> Result<Value> {
+        let fields = match &self.fields {
+            Some(v) => v,
+            None => return Ok(media),
+        };
+        let mut media = match media {
+            Value::Object(v) => v,
+            v => return Ok(v),
+        };
+        let mut projected = serde_json::Map::new();
+        for field in fields {
+            match media.remove(field) {
+                Some(v) => projected.insert(field.clone(), v),
+                None => {
+                    return Err(Error::ParamInvalid {
+                        got: field.clone(),
+                        field: "fields".into(),
+                        expect: "comma separated fields of Media".into(),
+                    })
+                }
+            };
+        }
+        Ok(Value::Object(projected))
+    }
+}
+
+impl SortedIds {
+    pub fn total(&self) -> usize {
+        self.ids.len()
+    }
+
+    pub fn ids(&self) -> impl Iterator<Item = u64> + '_ {
+        self.ids.iter().map(|v| v.1)
+    }
+
+    // Index of first id after `cursor`
+    pub fn position(&self, cursor: &str) -> Result<usize> {
+        let invalid = || Error::ParamInvalid {
+            got: cursor.to_string(),
+            field: "cursor".into(),
+            expect: "cursor of the same query".into(),
+        };
+        let decoded =
+            base64::decode_config(cursor, base64::URL_SAFE_NO_PAD).map_err(|_| invalid())?;
+        let cursor: Cursor = serde_json::from_slice(&decoded).map_err(|_| invalid())?;
+        if cursor.sort != self.query.sort || cursor.desc != self.query.desc {
+            return Err(invalid());
+        }
+        let after = |key: &Value, id: u64| {
+            let order = compare_values(key, &cursor.key).then(id.cmp(&cursor.id));
+            match self.query.desc {
+                true => order == Ordering::Less,
+                false => order == Ordering::Greater,
+            }
+        };
+        Ok(self
+            .ids
+            .iter()
+            .position(|(key, id)| after(key, *id))
+            .unwrap_or(self.ids.len()))
+    }
+
+    // Cursor pointing after the id at `index`
+    pub fn cursor(&self, index: usize) -> Option<String> {
+        let (key, id) = self.ids.get(index)?;
+        let cursor = Cursor {
+            sort: self.query.sort,
+            desc: self.query.desc,
+            key: key.clone(),
+            id: *id,
+        };
+        let encoded = serde_json::to_vec(&cursor).ok()?;
+        Some(base64::encode_config(encoded, base64::URL_SAFE_NO_PAD))
+    }
+
+    pub fn page(&self, start: usize, limit: Option<usize>) -> &[(Value, u64)] {
+        let start = start.min(self.ids.len());
+        let end = match limit {
+            Some(limit) => start.saturating_add(limit).min(self.ids.len()),
+            None => self.ids.len(),
+        };
+        &self.ids[start..end]
+    }
+}
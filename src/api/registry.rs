@@ -0,0 +1,91 @@
+use super::error::Result;
+use super::libraries::block;
+use serde::{Deserialize, Serialize};
+use shiromana_rs::misc::Uuid;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct RegistryEntry {
+    pub uuid: Uuid,
+    pub path: String,
+    pub registered_at: u64,
+}
+
+// Libraries opened or created through apis, reopened when server starts
+pub struct LibraryRegistry {
+    path: PathBuf,
+    entries: Mutex<Vec<RegistryEntry>>,
+}
+
+impl LibraryRegistry {
+    // Load registry from a json file, which is created if not existed
+    pub fn load<P: Into<PathBuf>>(path: P) -> Result<Self> {
+        let path = path.into();
+        let entries = if path.exists() {
+            serde_json::from_slice(&std::fs::read(&path)?)?
+        } else {
+            vec![]
+        };
+        Ok(Self {
+            path,
+            entries: Mutex::new(entries),
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    // Written on the blocking thread pool, locked through writing so saves never interleave
+    async fn save(self: &Arc<Self>) -> Result<()> {
+        let registry = self.clone();
+        block(move || {
+            let entries = registry.entries.lock().unwrap();
+            let tmp = registry.path.with_extension("json.tmp");
+            std::fs::write(&tmp, serde_json::to_vec_pretty(&*entries)?)?;
+            std::fs::rename(tmp, &registry.path)?;
+            Ok(())
+        })
+        .await
+    }
+
+    pub fn entries(&self) -> Vec<RegistryEntry> {
+        self.entries.lock().unwrap().clone()
+    }
+
+    pub async fn record<S: Into<String>>(self: &Arc<Self>, uuid: Uuid, path: S) -> Result<()> {
+        let path = path.into();
+        {
+            let mut entries = self.entries.lock().unwrap();
+            if entries.iter().any(|v| v.uuid == uuid && v.path == path) {
+                return Ok(());
+            }
+            entries.retain(|v| v.uuid != uuid);
+            entries.push(RegistryEntry {
+                uuid,
+                path,
+                registered_at: SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map(|v| v.as_secs())
+                    .unwrap_or(0),
+            });
+        }
+        self.save().await
+    }
+
+    // Returns false if library is not registered
+    pub async fn forget(self: &Arc<Self>, uuid: &Uuid) -> Result<bool> {
+        {
+            let mut entries = self.entries.lock().unwrap();
+            let count = entries.len();
+            entries.retain(|v| &v.uuid != uuid);
+            if entries.len() == count {
+                return Ok(false);
+            }
+        }
+        self.save().await?;
+        Ok(true)
+    }
+}
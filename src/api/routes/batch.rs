@@ -0,0 +1,489 @@
+use super::*;
+
+use super::super::query::{MediaQuery, MediaSort};
+use super::media::{get_media, query_medias, remove_media, update_media, BulkResult};
+use super::series::{
+    add_series_media, create_series, delete_series, get_series, remove_series_media,
+    trim_series_no, update_series_no,
+};
+use super::tag::{add_tag_media, create_tag, delete_tag, get_tag, remove_tag_media};
+use actix_web::post;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use shiromana_rs::library::Library;
+use shiromana_rs::media::Media;
+
+// Operations of a single batch, all of them hold the library lock together
+const BATCH_LIMIT: usize = 1000;
+
+// Route, its params and whether it could be undone
+type Operation = (&'static str, &'static [&'static str], bool);
+
+// Routes which could run inside a batch, which are the ones reading or changing a
+// single library. Not included are admin routes, uploads, routes streaming files or
+// events, thumbnails which run long, and jobs and webhooks which are not part of library.
+// `media/query` returns ids only, like with `ids_only`, and is never streamed.
+const OPERATIONS: [Operation; 16] = [
+    ("media/get", &["id"], true),
+    ("media/update", &["media"], true),
+    ("media/remove", &["id"], false),
+    ("media/query", &["q", "limit", "offset", "cursor", "sort", "desc"], true),
+    ("tag/get", &["tag"], true),
+    ("tag/create", &["caption", "comment"], true),
+    ("tag/delete", &["tag"], false),
+    ("tag/add_media", &["media", "q", "tag"], true),
+    ("tag/remove_media", &["media", "tag"], true),
+    ("series/get", &["series"], true),
+    ("series/create", &["caption", "comment"], true),
+    ("series/delete", &["series"], false),
+    (
+        "series/add_media",
+        &["media", "q", "series", "no", "unsorted"],
+        true,
+    ),
+    ("series/remove_media", &["media", "series"], false),
+    ("series/update_no", &["media", "series", "no", "insert"], false),
+    ("series/trim_no", &["series"], false),
+];
+
+#[derive(Deserialize)]
+pub struct BatchOperation {
+    api: String,
+    #[serde(default)]
+    params: Map<String, Value>,
+}
+
+impl ApiParam for Vec<BatchOperation> {
+    fn from_query(params: &QString, key: &str) -> Result<Self> {
+        let value: String = get_param(params, key)?;
+        param_from_json_str(&value, key)
+    }
+
+    fn schema() -> Value {
+        serde_json::json!({
+            "type": "array",
+            "maxItems": BATCH_LIMIT,
+            "items": {
+                "type": "object",
+                "required": ["api"],
+                "properties": {
+                    "api": {"type": "string", "enum": OPERATIONS.iter().map(|v| v.0).collect::<Vec<_>>()},
+                    "params": {"type": "object", "description": "Params of the api, except `library`."}
+                }
+            }
+        })
+    }
+}
+
+type Undo = Box<dyn FnOnce(&mut Library) -> Result<()> + Send>;
+
+// Result of an operation, with what reverts it
+struct Step {
+    status: ServerApiStatus,
+    // First failure of a bulk operation on medias
+    failure: Option<(String, ErrorCode)>,
+    result: Option<Value>,
+    undo: Option<Undo>,
+    event: Option<Event>,
+}
+
+impl Step {
+    fn new<F>(result: Option<Value>, undo: F) -> Self
+    where
+        F: FnOnce(&mut Library) -> Result<()> + Send + 'static,
+    {
+        Self {
+            status: ServerApiStatus::Success,
+            failure: None,
+            result,
+            undo: Some(Box::new(undo)),
+            event: None,
+        }
+    }
+
+    fn read(result: Value) -> Self {
+        Self {
+            status: ServerApiStatus::Success,
+            failure: None,
+            result: Some(result),
+            undo: None,
+            event: None,
+        }
+    }
+
+    fn irreversible() -> Self {
+        Self {
+            status: ServerApiStatus::Success,
+            failure: None,
+            result: None,
+            undo: None,
+            event: None,
+        }
+    }
+
+    // Medias added, with result of each if added in bulk, undone by removing the
+    // succeeded ones
+    fn added<F>(result: Option<BulkResult>, event: Event, undo: F) -> Result<Self>
+    where
+        F: FnOnce(&mut Library) -> Result<()> + Send + 'static,
+    {
+        let result = match result {
+            Some(v) => v,
+            None => return Ok(Self::new(None, undo).with_event(event)),
+        };
+        let step = Self {
+            status: result.status(),
+            failure: result.first_failure(),
+            ..Self::new(Some(serde_json::to_value(&result)?), undo)
+        };
+        Ok(match result.succeeded().is_empty() {
+            true => step,
+            false => step.with_event(event),
+        })
+    }
+
+    // Published once the batch is done and not rolled back
+    fn with_event(self, event: Event) -> Self {
+        Self {
+            event: Some(event),
+            ..self
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct OperationResult {
+    index: usize,
+    api: String,
+    status: ServerApiStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    result: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    code: Option<ErrorCode>,
+    // Succeeded but reverted since a later operation of atomic batch failed
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    rolled_back: bool,
+    // Reverting failed, so the change of operation is kept
+    #[serde(skip_serializing_if = "Option::is_none")]
+    rollback_error: Option<String>,
+}
+
+fn parse_uuid(v: String) -> Result<Uuid> {
+    v.parse::<Uuid>().map_err(|_| Error::ParamInvalid {
+        got: v,
+        field: "result".into(),
+        expect: "Uuid".into(),
+    })
+}
+
+fn run_operation(
+    lib: &mut Library,
+    library: Uuid,
+    api: &str,
+    params: &RequestParams,
+) -> Result<Step> {
+    Ok(match api {
+        "media/get" => Step::read(serde_json::to_value(get_media(lib, params.get("id")?)?)?),
+        "media/update" => {
+            let mut media: Media = params.get("media")?;
+            let id = media.id;
+            let mut old = get_media(lib, id)?;
+            update_media(lib, &mut media)?;
+            Step::new(None, move |lib| update_media(lib, &mut old))
+                .with_event(Event::MediaUpdated { library, media: id })
+        }
+        "media/remove" => {
+            let id: u64 = params.get("id")?;
+            remove_media(lib, id)?;
+            Step::irreversible().with_event(Event::MediaRemoved { library, media: id })
+        }
+        "media/query" => {
+            let query = MediaQuery {
+                sort: params.get::<Option<MediaSort>>("sort")?.unwrap_or(MediaSort::Id),
+                desc: params.get("desc")?,
+                fields: None,
+            };
+            let cursor: Option<String> = params.get("cursor")?;
+            let (page, _) = query_medias(
+                lib,
+                &query,
+                &params.get::<String>("q")?,
+                cursor.as_deref(),
+                params.get("offset")?,
+                params.get("limit")?,
+            )?;
+            Step::read(serde_json::to_value(page)?)
+        }
+        "tag/get" => Step::read(serde_json::to_value(get_tag(lib, &params.get("tag")?)?)?),
+        "tag/create" => {
+            let tag = create_tag(lib, params.get("caption")?, params.get("comment")?)?;
+            let uuid = parse_uuid(tag.clone())?;
+            Step::new(Some(tag.into()), move |lib| delete_tag(lib, uuid))
+                .with_event(Event::TagCreated { library, tag: uuid })
+        }
+        "tag/delete" => {
+            let tag: Uuid = params.get("tag")?;
+            delete_tag(lib, tag)?;
+            Step::irreversible().with_event(Event::TagDeleted { library, tag })
+        }
+        "tag/add_media" => {
+            let tag: Uuid = params.get("tag")?;
+            let (added, result) = add_tag_media(lib, params.get("media")?, params.get("q")?, &tag)?;
+            let event = Event::TagMediaAdded {
+                library,
+                tag,
+                media: added.clone(),
+            };
+            Step::added(result, event, move |lib| {
+                for &id in added.iter().rev() {
+                    remove_tag_media(lib, id, &tag)?;
+                }
+                Ok(())
+            })?
+        }
+        "tag/remove_media" => {
+            let (media, tag): (u64, Uuid) = (params.get("media")?, params.get("tag")?);
+            remove_tag_media(lib, media, &tag)?;
+            Step::new(None, move |lib| Ok(lib.add_tag(media, &tag)?)).with_event(
+                Event::TagMediaRemoved {
+                    library,
+                    tag,
+                    media: vec![media],
+                },
+            )
+        }
+        "series/get" => Step::read(serde_json::to_value(get_series(
+            lib,
+            &params.get("series")?,
+        )?)?),
+        "series/create" => {
+            let series = create_series(lib, params.get("caption")?, params.get("comment")?)?;
+            let uuid = parse_uuid(series.clone())?;
+            Step::new(Some(series.into()), move |lib| delete_series(lib, &uuid)).with_event(
+                Event::SeriesCreated {
+                    library,
+                    series: uuid,
+                },
+            )
+        }
+        "series/delete" => {
+            let series: Uuid = params.get("series")?;
+            delete_series(lib, &series)?;
+            Step::irreversible().with_event(Event::SeriesDeleted { library, series })
+        }
+        "series/add_media" => {
+            let series: Uuid = params.get("series")?;
+            let (added, result) = add_series_media(
+                lib,
+                params.get("media")?,
+                params.get("q")?,
+                &series,
+                params.get("no")?,
+                params.get("unsorted")?,
+            )?;
+            let event = Event::SeriesMediaAdded {
+                library,
+                series,
+                media: added.clone(),
+            };
+            Step::added(result, event, move |lib| {
+                for &id in added.iter().rev() {
+                    remove_series_media(lib, id, &series)?;
+                }
+                Ok(())
+            })?
+        }
+        "series/remove_media" => {
+            let (media, series): (u64, Uuid) = (params.get("media")?, params.get("series")?);
+            remove_series_media(lib, media, &series)?;
+            Step::irreversible().with_event(Event::SeriesMediaRemoved {
+                library,
+                series,
+                media: vec![media],
+            })
+        }
+        "series/update_no" => {
+            let (media, series): (u64, Uuid) = (params.get("media")?, params.get("series")?);
+            update_series_no(
+                lib,
+                media,
+                &series,
+                params.get("no")?,
+                params.get("insert")?,
+            )?;
+            Step::irreversible().with_event(Event::SeriesReordered { library, series })
+        }
+        "series/trim_no" => {
+            let series: Uuid = params.get("series")?;
+            trim_series_no(lib, &series)?;
+            Step::irreversible().with_event(Event::SeriesReordered { library, series })
+        }
+        api => {
+            return Err(Error::ParamInvalid {
+                got: api.to_string(),
+                field: "api".into(),
+                expect: "api which could run in batch".into(),
+            })
+        }
+    })
+}
+
+// Params of every operation, checked before anything runs
+fn prepare(operations: Vec<BatchOperation>, atomic: bool) -> Result<Vec<(String, RequestParams)>> {
+    if operations.len() > BATCH_LIMIT {
+        return Err(Error::ParamInvalid {
+            got: operations.len().to_string(),
+            field: "operations".into(),
+            expect: format!("at most {} operations", BATCH_LIMIT),
+        });
+    }
+    operations
+        .into_iter()
+        .enumerate()
+        .map(|(index, op)| {
+            let field = format!("operations[{}]", index);
+            let (_, keys, reversible) = OPERATIONS
+                .iter()
+                .find(|v| v.0 == op.api)
+                .ok_or_else(|| Error::ParamInvalid {
+                    got: op.api.clone(),
+                    field: field.clone(),
+                    expect: format!(
+                        "one of apis: {}",
+                        OPERATIONS.iter().map(|v| v.0).collect::<Vec<_>>().join(", ")
+                    ),
+                })?;
+            if atomic && !reversible {
+                return Err(Error::ParamInvalid {
+                    got: op.api,
+                    field,
+                    expect: "api which could be undone in atomic batch".into(),
+                });
+            }
+            let params = RequestParams::from_object(op.params, &field, keys)?;
+            Ok((op.api, params))
+        })
+        .collect()
+}
+
+// With `atomic`, the batch stops at the first failed operation and undoes the
+// succeeded ones in reverse order, so only operations which could be undone are
+// allowed. It is not a transaction: other clients never see the changes in between
+// as library is locked, but an undo could fail too, then the change is kept and
+// reported in `rollback_error` with status `PartialSuccess`.
+generate_api_broker!(batch, post, "batch",
+    params(library: Uuid, operations: Vec<BatchOperation>, atomic: bool),
+    (
+        library_uuid: Option<Uuid>,
+        opened_libraries: &Arc<OpenedLibraries>,
+        action: &str,
+        params: QString,
+        msg: ServerMessage,
+        state: &AppState
+    ) -> Result<ServerMessage>,
+    {
+        let operations = prepare(operations, atomic)?;
+        let (results, events, failure) = write_library!(opened_libraries, library, lib, {
+            let mut results = Vec::with_capacity(operations.len());
+            let mut undos = vec![];
+            let mut events = vec![];
+            let mut failure = None;
+            for (index, (api, params)) in operations.into_iter().enumerate() {
+                match run_operation(lib, library, &api, &params) {
+                    Ok(step) => {
+                        undos.extend(step.undo.map(|v| (index, v)));
+                        events.extend(step.event.map(|v| (index, v)));
+                        let (error, code) = match step.failure {
+                            Some((error, code)) => (Some(error), Some(code)),
+                            None => (None, None),
+                        };
+                        results.push(OperationResult {
+                            index,
+                            api,
+                            status: step.status,
+                            result: step.result,
+                            error,
+                            code,
+                            rolled_back: false,
+                            rollback_error: None,
+                        });
+                        // a partly failed bulk operation fails atomic batch too
+                        if let (true, Some(code)) = (atomic, code) {
+                            failure = Some(code);
+                            break;
+                        }
+                    }
+                    Err(e) => {
+                        results.push(OperationResult {
+                            index,
+                            api,
+                            status: ServerApiStatus::Failed,
+                            result: None,
+                            error: Some(e.to_string()),
+                            code: Some(e.code()),
+                            rolled_back: false,
+                            rollback_error: None,
+                        });
+                        if atomic {
+                            failure = Some(e.code());
+                            break;
+                        }
+                    }
+                }
+            }
+            // undo in reverse order, so each sees the state it was made in
+            if failure.is_some() {
+                for (index, undo) in undos.into_iter().rev() {
+                    match undo(lib) {
+                        Ok(_) => results[index].rolled_back = true,
+                        Err(e) => results[index].rollback_error = Some(e.to_string()),
+                    }
+                }
+                // only changes which are kept are announced
+                events.retain(|(index, _)| !results[*index].rolled_back);
+            }
+            Ok((results, events, failure))
+        })?;
+        for (_, event) in events {
+            state.events.publish(event);
+        }
+
+        let mut errors = results
+            .iter()
+            .filter_map(|v| v.error.clone().map(|e| (format!("operations[{}]", v.index), e)))
+            .collect::<Vec<_>>();
+        errors.extend(results.iter().filter_map(|v| {
+            v.rollback_error.clone().map(|e| (format!("rollback of operations[{}]", v.index), e))
+        }));
+        let succeeded = results
+            .iter()
+            .filter(|v| !matches!(v.status, ServerApiStatus::Failed) && !v.rolled_back)
+            .count();
+        let kept = results.iter().any(|v| v.rollback_error.is_some());
+        let msg = msg.with_serialized_result(&results)?.with_format("json");
+        if errors.is_empty() {
+            return Ok(msg);
+        }
+        let msg = ServerMessage {
+            error: Some(errors),
+            ..msg
+        };
+        let api_status = match (&failure, succeeded) {
+            // atomic batch keeps nothing unless an undo failed
+            (Some(_), _) if kept => ServerApiStatus::PartialSuccess,
+            (Some(_), _) | (None, 0) => ServerApiStatus::Failed,
+            (None, _) => ServerApiStatus::PartialSuccess,
+        };
+        let msg = ServerMessage {
+            status: api_status,
+            ..msg
+        };
+        Ok(match failure {
+            Some(code) => msg.with_error_code(code, Some("operations")),
+            None => msg,
+        })
+});
+
+register_services!(batch);
@@ -0,0 +1,103 @@
+use super::*;
+
+use actix_web::http::header::{self, EntityTag, Header, HttpDate, IfModifiedSince, IfNoneMatch};
+use sha2::{Digest, Sha256};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// Response with strong validators, answered with 304 if client already has it
+pub struct Cached<T> {
+    inner: T,
+    validators: Validators,
+}
+
+struct Validators {
+    etag: EntityTag,
+    last_modified: Option<SystemTime>,
+}
+
+fn seconds(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|v| v.as_secs())
+        .unwrap_or(0)
+}
+
+impl Validators {
+    // `If-None-Match` takes precedence over `If-Modified-Since`, see RFC 7232
+    fn not_modified(&self, req: &HttpRequest) -> bool {
+        if req.headers().contains_key(header::IF_NONE_MATCH) {
+            return match IfNoneMatch::parse(req) {
+                Ok(IfNoneMatch::Any) => true,
+                Ok(IfNoneMatch::Items(tags)) => tags.iter().any(|v| v.weak_eq(&self.etag)),
+                Err(_) => false,
+            };
+        }
+        match (self.last_modified, IfModifiedSince::parse(req)) {
+            (Some(modified), Ok(IfModifiedSince(since))) => {
+                seconds(modified) <= seconds(SystemTime::from(since))
+            }
+            _ => false,
+        }
+    }
+
+    fn apply(&self, resp: &mut HttpResponse) {
+        let headers = resp.headers_mut();
+        if let Ok(v) = header::HeaderValue::from_str(&self.etag.to_string()) {
+            headers.insert(header::ETAG, v);
+        }
+        if let Some(time) = self.last_modified {
+            if let Ok(v) = header::HeaderValue::from_str(&HttpDate::from(time).to_string()) {
+                headers.insert(header::LAST_MODIFIED, v);
+            }
+        }
+    }
+}
+
+impl<T: IntoResponse> Cached<T> {
+    // `tag` must change whenever content changes, like hash of media
+    pub fn new(inner: T, tag: &str) -> Self {
+        Self {
+            inner,
+            validators: Validators {
+                etag: EntityTag::strong(tag.to_string()),
+                last_modified: None,
+            },
+        }
+    }
+
+    pub fn of_content(inner: T, content: &[u8]) -> Self {
+        let digest = Sha256::digest(content);
+        let tag: String = digest[..16].iter().map(|v| format!("{:02x}", v)).collect();
+        Self::new(inner, &tag)
+    }
+
+    pub fn last_modified(mut self, time: SystemTime) -> Self {
+        // http dates have no subsecond
+        self.validators.last_modified = Some(UNIX_EPOCH + Duration::from_secs(seconds(time)));
+        self
+    }
+}
+
+impl<T: IntoResponse> IntoResponse for Cached<T> {
+    fn into_response(self, req: &HttpRequest) -> HttpResponse {
+        let mut resp = self.inner.into_response(req);
+        if !resp.status().is_success() {
+            return resp;
+        }
+        if self.validators.not_modified(req) {
+            // 304 keeps headers controlling cache, see RFC 7232
+            let mut builder = HttpResponse::NotModified();
+            for name in [header::CACHE_CONTROL, header::EXPIRES, header::VARY].iter() {
+                if let Some(v) = resp.headers().get(name) {
+                    builder.header(name.clone(), v.clone());
+                }
+            }
+            resp = builder.body(actix_web::body::Body::None);
+        }
+        self.validators.apply(&mut resp);
+        resp
+    }
+
+    fn response_spec() -> serde_json::Value {
+        T::response_spec()
+    }
+}
@@ -0,0 +1,67 @@
+use super::*;
+
+use actix_web::get;
+use futures::stream::{self, StreamExt};
+use std::time::Duration;
+use tokio::sync::broadcast::error::RecvError;
+
+// Comment sent when idle, so proxies keep the connection
+const KEEP_ALIVE: Duration = Duration::from_secs(15);
+
+// Server-sent events, each `data` is a json of `Event`
+pub struct EventStream(HttpResponse);
+
+impl IntoResponse for EventStream {
+    fn into_response(self, _: &HttpRequest) -> HttpResponse {
+        self.0
+    }
+
+    fn response_spec() -> serde_json::Value {
+        serde_json::json!({
+            "description": "Server-sent events, `data` is json with field `type` like `media_added`. \
+                Type `lagged` means `missed` events are dropped and views should be refreshed.",
+            "content": {"text/event-stream": {"schema": {"type": "string"}}}
+        })
+    }
+}
+
+fn to_data(value: &serde_json::Value) -> web::Bytes {
+    format!("data: {}\n\n", value).into()
+}
+
+generate_api_broker!(events, get, "events",
+    params(library: Option<Uuid>),
+    (
+        library_uuid: Option<Uuid>,
+        opened_libraries: &Arc<OpenedLibraries>,
+        action: &str,
+        params: QString,
+        msg: ServerMessage,
+        state: &AppState
+    ) -> Result<EventStream>,
+    {
+        let changes = stream::unfold(state.events.subscribe(), move |mut receiver| async move {
+            loop {
+                let data = match receiver.recv().await {
+                    Ok(event) if library.map_or(false, |v| &v != event.library()) => continue,
+                    Ok(event) => serde_json::to_value(&event).ok()?,
+                    Err(RecvError::Lagged(missed)) => {
+                        serde_json::json!({"type": "lagged", "missed": missed})
+                    }
+                    Err(RecvError::Closed) => return None,
+                };
+                return Some((to_data(&data), receiver));
+            }
+        });
+        let keep_alive = actix_web::rt::time::interval(KEEP_ALIVE)
+            .map(|_| web::Bytes::from_static(b": keep-alive\n\n"));
+        let body = stream::select(changes, keep_alive).map(Ok::<_, actix_web::Error>);
+        Ok(EventStream(
+            HttpResponse::Ok()
+                .content_type("text/event-stream")
+                .header(actix_web::http::header::CACHE_CONTROL, "no-cache")
+                .streaming(body.boxed_local()),
+        ))
+});
+
+register_services!(events);
@@ -0,0 +1,51 @@
+use super::*;
+
+use super::super::jobs::JobState;
+use actix_web::{get, post};
+
+generate_api_broker!(jobs_get, get, "jobs/get",
+    params(library: Uuid, job: u64),
+    (
+        library_uuid: Option<Uuid>,
+        opened_libraries: &Arc<OpenedLibraries>,
+        action: &str,
+        params: QString,
+        msg: ServerMessage,
+        state: &AppState
+    ) -> Result<ServerMessage>,
+    {
+        Ok(msg.with_serialized_result(&state.jobs.get(&library, job)?)?.with_format("json"))
+});
+
+generate_api_broker!(jobs_cancel, post, "jobs/cancel",
+    params(library: Uuid, job: u64),
+    (
+        library_uuid: Option<Uuid>,
+        opened_libraries: &Arc<OpenedLibraries>,
+        action: &str,
+        params: QString,
+        msg: ServerMessage,
+        state: &AppState
+    ) -> Result<ServerMessage>,
+    {
+        Ok(msg.with_serialized_result(&state.jobs.cancel(&library, job)?)?.with_format("json"))
+});
+
+// Jobs of all libraries without `library`
+generate_api_broker!(jobs_list, get, "jobs/list",
+    params(library: Option<Uuid>, job_state = "state": Option<String>),
+    (
+        library_uuid: Option<Uuid>,
+        opened_libraries: &Arc<OpenedLibraries>,
+        action: &str,
+        params: QString,
+        msg: ServerMessage,
+        state: &AppState
+    ) -> Result<ServerMessage>,
+    {
+        let job_state = job_state.map(|v| v.parse::<JobState>()).transpose()?;
+        let jobs = state.jobs.list(library.as_ref(), job_state);
+        Ok(msg.with_serialized_result(&jobs)?.with_format("json"))
+});
+
+register_services!(jobs_get, jobs_cancel, jobs_list);
@@ -2,23 +2,25 @@ use super::*;
 
 use log::info;
 use std::{io, path::PathBuf};
-use tokio::sync::Mutex;
 
+use super::super::registry::RegistryEntry;
 use actix_web::{get, post};
 use shiromana_rs::library::{Library, LibraryFeatures};
 use shiromana_rs::media::{Media, MediaType};
 use shiromana_rs::misc::{Error as LibError, Uuid};
 
 generate_api_broker!(library_open, get, "library/open",
+    params(path: String),
     (
         library_uuid: Option<Uuid>,
-        opened_libraries: &Arc<Mutex<HashMap<Uuid, Library>>>,
+        opened_libraries: &Arc<OpenedLibraries>,
         action: &str,
         params: QString,
-        msg: ServerMessage
+        msg: ServerMessage,
+        state: &AppState
     ) -> Result<ServerMessage>,
     {
-        let path: String = get_param(&params, "path")?;
+        let path = state.allowed_roots.check("path", &path)?.to_string_lossy().to_string();
         if !PathBuf::from(&path).is_dir() {
             return Err(Error::NotExisted {
                 got: path,
@@ -26,57 +28,51 @@ generate_api_broker!(library_open, get, "library/open",
                 expect: "Folder".to_string(),
             });
         }
-        let lib = Library::open(path)?;
+        let lib = {
+            let path = path.clone();
+            opened_libraries.spawn_blocking(move || Ok(Library::open(path)?)).await?
+        };
         let lib_uuid = lib.uuid.clone();
-        take_mutex!(opened_libraries, {
-            let uuid = lib.uuid.clone();
-            opened_libraries.insert(uuid, lib);
-        });
-        Ok(msg.with_library(lib_uuid))
+        if opened_libraries.insert(lib) {
+            state.events.publish(Event::LibraryOpened { library: lib_uuid });
+        }
+        Ok(record_library(state, lib_uuid, path, msg.with_library(lib_uuid)).await)
 });
 
 generate_api_broker!(library_close, post, "library/close",
+    params(library: Uuid),
     (
         library_uuid: Option<Uuid>,
-        opened_libraries: &Arc<Mutex<HashMap<Uuid, Library>>>,
+        opened_libraries: &Arc<OpenedLibraries>,
         action: &str,
         params: QString,
-        msg: ServerMessage
+        msg: ServerMessage,
+        state: &AppState
     ) -> Result<ServerMessage>,
     {
-        if let Some(library_uuid) = library_uuid{
-            let mut opened_libraries = opened_libraries.lock().await;
-            if opened_libraries.contains_key(&library_uuid) {
-                if let Some(v) = opened_libraries.remove(&library_uuid) {
-                    drop(v);
-                    // Ok(msg.with_library(library_uuid))
-                    Ok(msg)
-                } else {
-                    Ok(msg.with_single_error(
-                        "library",
-                        "Cannot remove opened library.",
-                        Some(library_uuid),
-                        None
-                    ))
-                }
-            } else {
-                Err(Error::LibraryNotOpened(library_uuid))
+        match opened_libraries.remove(&library) {
+            Some(v) => {
+                drop(v);
+                state.events.publish(Event::LibraryClosed { library });
+                // Ok(msg.with_library(library))
+                Ok(msg)
             }
-        }else {
-            Err(Error::NoParam("library".to_string()))
+            None => Err(Error::LibraryNotOpened(library))
         }
 });
 
 generate_api_broker!(library_create, get, "library/create",
+    params(path: String, name: String, master: Option<String>, media_folder: Option<String>, features: Option<String>),
     (
         library_uuid: Option<Uuid>,
-        opened_libraries: &Arc<Mutex<HashMap<Uuid, Library>>>,
+        opened_libraries: &Arc<OpenedLibraries>,
         action: &str,
         params: QString,
-        msg: ServerMessage
+        msg: ServerMessage,
+        state: &AppState
     ) -> Result<ServerMessage>,
     {
-        let path: String = get_param(&params, "path")?;
+        let path = state.allowed_roots.check("path", &path)?.to_string_lossy().to_string();
         if PathBuf::from(&path).exists() {
             return Err(Error::AlreadyExisted{
                 got: path,
@@ -84,23 +80,99 @@ generate_api_broker!(library_create, get, "library/create",
             })
         }
 
-        let features = match get_param_option::<String>(&params, "features")? {
+        let features = match features {
             Some(s) => LibraryFeatures::from_str(s.as_str())?,
             None => LibraryFeatures::new()
         };
 
-        let lib = Library::create(
-            path,
-            get_param(&params,"name")?,
-            get_param_option(&params, "master")?,
-            get_param_option(&params, "media_folder")?,
-            features
-        )?;
+        let lib = {
+            let path = path.clone();
+            opened_libraries.spawn_blocking(move || {
+                Ok(Library::create(path, name, master, media_folder, features)?)
+            }).await?
+        };
         let uuid = lib.uuid.clone();
-        take_mutex!(opened_libraries, {
-            opened_libraries.insert(lib.uuid, lib);
-        });
-        Ok(msg.with_library(uuid))
+        if opened_libraries.insert(lib) {
+            state.events.publish(Event::LibraryOpened { library: uuid });
+        }
+        Ok(record_library(state, uuid, path, msg.with_library(uuid)).await)
+});
+
+generate_api_broker!(library_forget, post, "library/forget",
+    params(library: Uuid, close: bool),
+    (
+        library_uuid: Option<Uuid>,
+        opened_libraries: &Arc<OpenedLibraries>,
+        action: &str,
+        params: QString,
+        msg: ServerMessage,
+        state: &AppState
+    ) -> Result<ServerMessage>,
+    {
+        if !state.registry.forget(&library).await? {
+            return Err(Error::NotExisted {
+                got: library.to_string(),
+                field: "library".into(),
+                expect: "registered library".into()
+            });
+        }
+        if close && opened_libraries.remove(&library).is_some() {
+            state.events.publish(Event::LibraryClosed { library });
+        }
+        Ok(msg)
 });
 
-register_services!(library_open, library_close, library_create);
+#[derive(serde::Serialize)]
+struct RegistryInfo {
+    #[serde(flatten)]
+    entry: RegistryEntry,
+    opened: bool,
+}
+
+generate_api_broker!(library_registry, get, "library/registry",
+    params(stream: bool),
+    (
+        library_uuid: Option<Uuid>,
+        opened_libraries: &Arc<OpenedLibraries>,
+        action: &str,
+        params: QString,
+        msg: ServerMessage,
+        state: &AppState
+    ) -> Result<Either<ServerMessage, NdJson>>,
+    {
+        let entries = state.registry.entries();
+        let entries = entries.into_iter().map(|entry| RegistryInfo {
+            opened: opened_libraries.contains(&entry.uuid),
+            entry
+        }).collect::<Vec<_>>();
+        match stream {
+            true => Ok(Either::B(NdJson::from_items(entries))),
+            false => Ok(Either::A(msg.with_serialized_result(&entries)?.with_format("json")))
+        }
+});
+
+// Remember opened library so it is reopened when server restarts
+async fn record_library(
+    state: &AppState,
+    uuid: Uuid,
+    path: String,
+    msg: ServerMessage,
+) -> ServerMessage {
+    match state.registry.record(uuid, path).await {
+        Ok(_) => msg,
+        Err(e) => msg.with_single_error_but_partial_success(
+            "registry",
+            format!("Failed to record library into registry due to {}.", e),
+            Some(uuid),
+            None,
+        ),
+    }
+}
+
+register_services!(
+    library_open,
+    library_close,
+    library_create,
+    library_forget,
+    library_registry
+);
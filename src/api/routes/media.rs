@@ -1,6 +1,10 @@
 use super::*;
 
+use super::super::libraries::block;
+use super::super::query::{MediaIds, MediaQuery, MediaQueryResult, MediaSort, QUERY_BATCH};
+use actix_multipart::Multipart;
 use actix_web::{get, post};
+use futures::StreamExt;
 use mime::Mime;
 use mime_sniffer::MimeTypeSniffer;
 use serde::Serialize;
@@ -8,48 +12,279 @@ use shiromana_rs::library::Library;
 use shiromana_rs::media::{Media, MediaType};
 use shiromana_rs::misc::Error as LibError;
 use std::io::prelude::*;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+// Guess media type from the magic bytes of file
+fn guess_media_type<P: AsRef<path::Path>>(path: P) -> Result<Option<&'static str>> {
+    let mut file = std::fs::File::open(path)?;
+    let mut buffer = [0; 64]; // 64 bytes is enough I guess
+    file.read(&mut buffer)?;
+    let mime_type = match buffer.sniff_mime_type() {
+        Some(s) => s,
+        None => return Ok(None),
+    }
+    .parse::<Mime>()
+    .unwrap(); // not risky unwrap, believe sniffer
+    Ok(Some(match mime_type.type_() {
+        mime::IMAGE => "image",
+        mime::TEXT => "text",
+        mime::AUDIO => "audio",
+        mime::VIDEO => "video",
+        _ => "other",
+    }))
+}
+
+// Sent as json string in query for compatibility, or as object in json body
+impl ApiParam for Media {
+    fn from_query(params: &QString, key: &str) -> Result<Self> {
+        let value: String = get_param(params, key)?;
+        param_from_json_str(&value, key)
+    }
+
+    fn schema() -> serde_json::Value {
+        serde_json::json!({"type": "object", "description": "Media as returned by `media/get`."})
+    }
+}
+
+impl ApiParam for MediaSort {
+    fn from_query(params: &QString, key: &str) -> Result<Self> {
+        params
+            .get(key)
+            .ok_or_else(|| Error::NoParam(key.to_string()))?
+            .parse()
+    }
+
+    fn from_json(value: &serde_json::Value, key: &str) -> Result<Self> {
+        match value.as_str() {
+            Some(v) => v.parse(),
+            None => Err(Error::ParamInvalid {
+                got: value.to_string(),
+                field: key.to_string(),
+                expect: "string".into(),
+            }),
+        }
+    }
+
+    fn schema() -> serde_json::Value {
+        serde_json::json!({"type": "string", "enum": MediaSort::ALL})
+    }
+}
+
+impl ApiParam for MediaIds {
+    fn from_query(params: &QString, key: &str) -> Result<Self> {
+        params
+            .get(key)
+            .ok_or_else(|| Error::NoParam(key.to_string()))?
+            .parse()
+    }
+
+    fn from_json(value: &serde_json::Value, _: &str) -> Result<Self> {
+        std::convert::TryFrom::try_from(value.clone())
+    }
+
+    fn schema() -> serde_json::Value {
+        serde_json::json!({
+            "description": "Media id, or list and ranges of ids like `1,4-9`.",
+            "oneOf": [
+                {"type": "integer", "format": "int64", "minimum": 0},
+                {"type": "string"},
+                {"type": "array", "items": {"type": "integer", "format": "int64", "minimum": 0}}
+            ]
+        })
+    }
+}
+
+// Medias given by ids or matched by query `q`, exactly one of them is needed
+pub(super) fn select_medias(
+    lib: &Library,
+    media: Option<MediaIds>,
+    q: Option<String>,
+) -> Result<MediaIds> {
+    match (media, q) {
+        (Some(media), None) => Ok(media),
+        (None, Some(q)) => Ok(MediaIds {
+            ids: lib.query_media(&q)?,
+            single: false,
+        }),
+        (None, None) => Err(Error::NoParam("media".into())),
+        (Some(_), Some(q)) => Err(Error::ParamInvalid {
+            got: q,
+            field: "q".into(),
+            expect: "either media or q".into(),
+        }),
+    }
+}
 
 #[derive(Serialize)]
-struct MediaQueryResult {
+struct BulkFailure {
     id: u64,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    media: Option<Media>,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    error: Option<String>,
+    error: String,
+    code: ErrorCode,
+}
+
+// Outcome of applying one operation to many medias
+#[derive(Serialize, Default)]
+pub(super) struct BulkResult {
+    succeeded: Vec<u64>,
+    failed: Vec<BulkFailure>,
+}
+
+impl BulkResult {
+    pub(super) fn succeeded(&self) -> &[u64] {
+        &self.succeeded
+    }
+
+    pub(super) fn apply<F>(ids: &[u64], mut f: F) -> Self
+    where
+        F: FnMut(u64) -> Result<()>,
+    {
+        let mut result = Self::default();
+        for &id in ids {
+            match f(id) {
+                Ok(_) => result.succeeded.push(id),
+                Err(e) => result.failed.push(BulkFailure {
+                    id,
+                    error: e.to_string(),
+                    code: e.code(),
+                }),
+            }
+        }
+        result
+    }
+
+    pub(super) fn status(&self) -> ServerApiStatus {
+        match (self.succeeded.is_empty(), self.failed.is_empty()) {
+            (_, true) => ServerApiStatus::Success,
+            (true, false) => ServerApiStatus::Failed,
+            (false, false) => ServerApiStatus::PartialSuccess,
+        }
+    }
+
+    pub(super) fn first_failure(&self) -> Option<(String, ErrorCode)> {
+        self.failed
+            .first()
+            .map(|v| (format!("media {}: {}", v.id, v.error), v.code))
+    }
+
+    pub(super) fn into_message(self, msg: ServerMessage) -> Result<ServerMessage> {
+        let mut data = HashMap::new();
+        data.insert("succeeded".to_string(), self.succeeded.len().to_string());
+        data.insert("failed".to_string(), self.failed.len().to_string());
+        let api_status = self.status();
+        let error = match self.failed.is_empty() {
+            true => None,
+            false => Some(
+                self.failed
+                    .iter()
+                    .map(|v| (format!("media {}", v.id), v.error.clone()))
+                    .collect(),
+            ),
+        };
+        let msg = msg
+            .with_data(data)
+            .with_serialized_result(&self)?
+            .with_format("json");
+        Ok(ServerMessage {
+            status: api_status,
+            error,
+            ..msg
+        })
+    }
+}
+
+// Bodies of routes below working on a locked library, shared with operations of `batch`
+
+pub(super) fn get_media(lib: &Library, id: u64) -> Result<Media> {
+    lib.get_media(id).for_media(id)
+}
+
+pub(super) fn remove_media(lib: &mut Library, id: u64) -> Result<()> {
+    lib.remove_media(id).for_media(id)
+}
+
+pub(super) fn update_media(lib: &mut Library, media: &mut Media) -> Result<()> {
+    let id = media.id;
+    lib.update_media(media).for_media(id)
+}
+
+// Ids of page of medias matched by `q` starting at `cursor` or `offset`, with
+// total, offset and cursor of next page
+pub(super) fn query_medias(
+    lib: &Library,
+    query: &MediaQuery,
+    q: &str,
+    cursor: Option<&str>,
+    offset: Option<usize>,
+    limit: Option<usize>,
+) -> Result<(Vec<u64>, HashMap<String, String>)> {
+    if let (Some(_), Some(cursor)) = (offset, cursor) {
+        return Err(Error::ParamInvalid {
+            got: cursor.to_string(),
+            field: "cursor".into(),
+            expect: "either cursor or offset".into(),
+        });
+    }
+    let sorted = query.run(lib, q)?;
+    let start = match cursor {
+        Some(v) => sorted.position(v)?,
+        None => offset.unwrap_or(0),
+    };
+    let page = sorted
+        .page(start, limit)
+        .iter()
+        .map(|v| v.1)
+        .collect::<Vec<_>>();
+    let end = start + page.len();
+
+    let mut data = HashMap::new();
+    data.insert("total".to_string(), sorted.total().to_string());
+    data.insert("offset".to_string(), start.to_string());
+    if end < sorted.total() && !page.is_empty() {
+        if let Some(next) = sorted.cursor(end - 1) {
+            data.insert("cursor".to_string(), next);
+        }
+    }
+    Ok((page, data))
 }
 
 generate_api_broker!(media_get, get, "media/get",
+    params(library: Uuid, id: u64),
     (
         library_uuid: Option<Uuid>,
-        opened_libraries: &Arc<Mutex<HashMap<Uuid, Library>>>,
+        opened_libraries: &Arc<OpenedLibraries>,
         action: &str,
         params: QString,
-        msg: ServerMessage
+        msg: ServerMessage,
+        state: &AppState
     ) -> Result<ServerMessage>,
     {
-        let library_uuid = library_uuid
-            .ok_or_else(|| Error::NoParam("Library".into()))?;
-        let id = get_param(&params, "id")?;
-        let media = take_mutex!(opened_libraries, {
-            let lib = opened_libraries.get(&library_uuid)
-                .ok_or_else(|| Error::LibraryNotOpened(library_uuid))?;
-            lib.get_media(id)
+        let media = read_library!(opened_libraries, library, lib, {
+            get_media(lib, id)
         })?;
-        Ok(msg.with_media(id).with_result(serde_json::to_string(&media)?).with_format("json"))
+        Ok(msg.with_media(id).with_serialized_result(&media)?.with_format("json"))
 });
 
 generate_api_broker!(media_add, post, "media/add",
+    params(
+        library: Uuid,
+        path: String,
+        kind = "type": Option<String>,
+        sub_type: Option<String>,
+        type_addition: Option<String>,
+        caption: Option<String>,
+        comment: Option<String>,
+        delete: bool
+    ),
     (
         library_uuid: Option<Uuid>,
-        opened_libraries: &Arc<Mutex<HashMap<Uuid, Library>>>,
+        opened_libraries: &Arc<OpenedLibraries>,
         action: &str,
         params: QString,
-        msg: ServerMessage
+        msg: ServerMessage,
+        state: &AppState
     ) -> Result<ServerMessage>,
     {
-        let library_uuid = library_uuid
-            .ok_or_else(|| Error::NoParam("Library".into()))?;
-        let path: String = get_param(&params, "path")?;
+        let path = state.allowed_roots.check("path", &path)?.to_string_lossy().to_string();
         if !path::PathBuf::from(&path).is_file() {
             return Err(Error::NotExisted{
                 got: path,
@@ -58,51 +293,31 @@ generate_api_broker!(media_add, post, "media/add",
             });
         }
 
-        let kind = match get_param_option::<String>(&params, "type")? {
+        let kind = match kind {
             Some(v) => v,
-            None => {
-                // guess format
-                let mut file = std::fs::File::open(&path)?;
-                let mut buffer = [0; 64]; // 64 bytes is enough I guess
-                file.read(&mut buffer)?;
-                let mime_type = match buffer.sniff_mime_type() {
-                    Some(s) => s,
-                    None => return Ok(
-                        msg.with_single_error(
-                            "Media",
-                            "Cannot guess file type, please provide parameter `type`.",
-                            Some(library_uuid),
-                            None
-                        ))
-                }.parse::<Mime>().unwrap(); // not risky unwrap, believe sniffer
-                match mime_type.type_() {
-                    mime::IMAGE => "image",
-                    mime::TEXT => "text",
-                    mime::AUDIO => "audio",
-                    mime::VIDEO => "video",
-                    _ => "other"
-                }.into()
+            None => match {
+                let path = path.clone();
+                opened_libraries.spawn_blocking(move || guess_media_type(path)).await?
+            } {
+                Some(v) => v.into(),
+                // file type could not be guessed, so `type` is required
+                None => return Err(Error::NoParam("type".into()))
             }
         };
-        let id = take_mutex!(opened_libraries, {
-            let mut lib = opened_libraries.get_mut(&library_uuid)
-                .ok_or_else(|| Error::LibraryNotOpened(library_uuid))?;
-            lib.add_media(
-                path.clone(),
-                MediaType::from_str(kind.as_str())?,
-                get_param_option(&params, "sub_type")?,
-                get_param_option(&params, "type_addition")?,
-                get_param_option(&params, "caption")?,
-                get_param_option(&params, "comment")?
-            )
+        let kind = MediaType::from_str(kind.as_str())?;
+        let media_path = path.clone();
+        let id = write_library!(opened_libraries, library, lib, {
+            Ok(lib.add_media(media_path, kind, sub_type, type_addition, caption, comment)?)
         })?;
-        if get_param_bool(&params, "delete")? == true {
+        state.events.publish(Event::MediaAdded { library, media: id });
+        if delete {
             // remove original file
-            if let Err(e) = std::fs::remove_file(&path) {
+            let removed = path.clone();
+            if let Err(e) = block(move || Ok(std::fs::remove_file(removed)?)).await {
                 return Ok(msg.with_single_error_but_partial_success(
                     "Media",
                     format!("Failed to remove original file `{}` due to {}.", path, e),
-                    Some(library_uuid),
+                    Some(library),
                     Some(id)
                 ))
             }
@@ -111,89 +326,365 @@ generate_api_broker!(media_add, post, "media/add",
 });
 
 generate_api_broker!(media_remove, post, "media/remove",
+    params(library: Uuid, id: u64),
     (
         library_uuid: Option<Uuid>,
-        opened_libraries: &Arc<Mutex<HashMap<Uuid, Library>>>,
+        opened_libraries: &Arc<OpenedLibraries>,
         action: &str,
         params: QString,
-        msg: ServerMessage
+        msg: ServerMessage,
+        state: &AppState
     ) -> Result<ServerMessage>,
     {
-        let library_uuid = library_uuid
-            .ok_or_else(|| Error::NoParam("Library".into()))?;
-        let id = get_param(&params, "id")?;
-        take_mutex!(opened_libraries, {
-            let mut lib = opened_libraries.get_mut(&library_uuid)
-                .ok_or_else(|| Error::LibraryNotOpened(library_uuid))?;
-            lib.remove_media(id)?;
-        });
+        write_library!(opened_libraries, library, lib, {
+            remove_media(lib, id)
+        })?;
+        state.events.publish(Event::MediaRemoved { library, media: id });
         Ok(msg.with_media(id))
 });
 
 generate_api_broker!(media_update, post, "media/update",
+    params(library: Uuid, media: Media),
     (
         library_uuid: Option<Uuid>,
-        opened_libraries: &Arc<Mutex<HashMap<Uuid, Library>>>,
+        opened_libraries: &Arc<OpenedLibraries>,
         action: &str,
         params: QString,
-        msg: ServerMessage
+        msg: ServerMessage,
+        state: &AppState
     ) -> Result<ServerMessage>,
     {
-        let library_uuid = library_uuid
-            .ok_or_else(|| Error::NoParam("Library".into()))?;
-        // let id = get_param(&params, "id")?;
-        let media = get_param::<String>(&params, "media")?;
-        let mut media: Media = serde_json::from_str(media.as_str())?;
+        let mut media = media;
         let id = media.id;
 
-        take_mutex!(opened_libraries, {
-            let mut lib = opened_libraries.get_mut(&library_uuid)
-                .ok_or_else(|| Error::LibraryNotOpened(library_uuid))?;
-            lib.update_media(&mut media)?;
-        });
+        write_library!(opened_libraries, library, lib, {
+            update_media(lib, &mut media)
+        })?;
+        state.events.publish(Event::MediaUpdated { library, media: id });
 
         Ok(msg.with_media(id))
 });
 
 generate_api_broker!(media_query, get, "media/query",
+    params(
+        library: Uuid,
+        q: String,
+        limit: Option<usize>,
+        offset: Option<usize>,
+        cursor: Option<String>,
+        sort: Option<MediaSort>,
+        desc: bool,
+        fields: Option<String>,
+        ids_only: bool,
+        stream: bool
+    ),
     (
         library_uuid: Option<Uuid>,
-        opened_libraries: &Arc<Mutex<HashMap<Uuid, Library>>>,
+        opened_libraries: &Arc<OpenedLibraries>,
         action: &str,
         params: QString,
-        msg: ServerMessage
-    ) -> Result<ServerMessage>,
+        msg: ServerMessage,
+        state: &AppState
+    ) -> Result<Either<ServerMessage, NdJson>>,
     {
-         let library_uuid = library_uuid
-            .ok_or_else(|| Error::NoParam("Library".into()))?;
-        let q:String = get_param(&params, "q")?;
-        let results = take_mutex!(opened_libraries, {
-            let lib = opened_libraries.get(&library_uuid)
-                .ok_or_else(|| Error::LibraryNotOpened(library_uuid))?;
-            let ids = lib.query_media(q.as_str())?;
-            lib.get_medias(ids.into_iter())
+        let query = MediaQuery {
+            sort: sort.unwrap_or(MediaSort::Id),
+            desc,
+            fields: fields.map(|v| {
+                v.split(',').map(|v| v.trim().to_string()).filter(|v| !v.is_empty()).collect()
+            }),
+        };
+        let stream_query = query.clone();
+        let (page, results, data) = read_library!(opened_libraries, library, lib, {
+            let (page, data) = query_medias(lib, &query, &q, cursor.as_deref(), offset, limit)?;
+            // streamed medias are loaded batch by batch later
+            let results = match (stream, ids_only) {
+                (true, _) => None,
+                (false, true) => Some(serde_json::to_value(&page)?),
+                (false, false) => Some(serde_json::to_value(query.load(lib, &page)?)?)
+            };
+            Ok((page, results, data))
+        })?;
+
+        if let Some(results) = results {
+            return Ok(Either::A(
+                msg.with_format("json").with_data(data).with_serialized_result(&results)?
+            ));
+        }
+        let resp = match ids_only {
+            true => NdJson::from_items(page),
+            false => NdJson::from_batches(
+                stream_medias(opened_libraries.clone(), library, page, stream_query)
+            )
+        };
+        Ok(Either::B(data.into_iter().fold(resp, |resp, (k, v)| match k.as_str() {
+            "total" => resp.header("x-total-count", v),
+            "offset" => resp.header("x-offset", v),
+            _ => resp.header("x-cursor", v),
+        })))
+});
+
+// Lock is taken for each batch, so writers are not blocked through the whole listing
+pub(super) fn stream_medias(
+    opened_libraries: Arc<OpenedLibraries>,
+    library: Uuid,
+    ids: Vec<u64>,
+    query: MediaQuery,
+) -> impl futures::Stream<Item = Result<Vec<MediaQueryResult>>> {
+    futures::stream::unfold(0, move |start| {
+        let opened_libraries = opened_libraries.clone();
+        let query = query.clone();
+        let batch = ids[start.min(ids.len())..(start + QUERY_BATCH).min(ids.len())].to_vec();
+        async move {
+            if batch.is_empty() {
+                return None;
+            }
+            let end = start + batch.len();
+            Some((
+                opened_libraries
+                    .read(&library, move |lib| query.load(lib, &batch))
+                    .await,
+                end,
+            ))
+        }
+    })
+}
+
+// Text fields of upload form should never be this large
+const UPLOAD_FIELD_LIMIT: usize = 64 * 1024;
+
+static UPLOAD_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+// Temporary folder holding a single uploaded file, removed on drop
+struct UploadDir(path::PathBuf);
+
+impl UploadDir {
+    async fn new() -> Result<Self> {
+        let dir = std::env::temp_dir().join(format!(
+            "shiromana-upload-{}-{}",
+            std::process::id(),
+            UPLOAD_COUNTER.fetch_add(1, Ordering::SeqCst)
+        ));
+        let created = dir.clone();
+        block(move || Ok(std::fs::create_dir_all(created)?)).await?;
+        Ok(Self(dir))
+    }
+}
+
+impl Drop for UploadDir {
+    fn drop(&mut self) {
+        // blocking task is skipped if nobody waits for it, so it is awaited in a spawned one
+        let dir = std::mem::take(&mut self.0);
+        actix_web::rt::spawn(async move {
+            let _ = block(move || Ok(std::fs::remove_dir_all(dir)?)).await;
         });
-        let results = results.into_iter().map(|(id, media)| {
-            match media {
-                Ok(media) => MediaQueryResult {
+    }
+}
+
+// Receive multipart form, write field `file` into `dir` and collect other fields as params
+async fn receive_upload(
+    mut payload: Multipart,
+    dir: &UploadDir,
+    limit: u64,
+) -> Result<(Option<path::PathBuf>, QString)> {
+    let mut filepath = None;
+    let mut fields: Vec<(String, String)> = vec![];
+    let mut received: u64 = 0;
+    while let Some(field) = payload.next().await {
+        let mut field = field?;
+        let disposition = field
+            .content_disposition()
+            .ok_or_else(|| Error::NoParam("Content-Disposition".into()))?;
+        let name = disposition.get_name().unwrap_or_default().to_string();
+        if name == "file" {
+            let filename = disposition
+                .get_filename()
+                .and_then(|v| path::Path::new(v).file_name())
+                .ok_or_else(|| Error::NoParam("filename".into()))?
+                .to_owned();
+            let path = dir.0.join(filename);
+            let created = path.clone();
+            let mut file = block(move || Ok(std::fs::File::create(created)?)).await?;
+            while let Some(chunk) = field.next().await {
+                let chunk = chunk?;
+                received += chunk.len() as u64;
+                if received > limit {
+                    return Err(Error::PayloadTooLarge(limit));
+                }
+                // file is moved into the blocking task and back
+                file = block(move || {
+                    file.write_all(&chunk)?;
+                    Ok(file)
+                })
+                .await?;
+            }
+            filepath = Some(path);
+        } else {
+            let mut value = vec![];
+            while let Some(chunk) = field.next().await {
+                let chunk = chunk?;
+                if value.len() + chunk.len() > UPLOAD_FIELD_LIMIT {
+                    return Err(Error::PayloadTooLarge(UPLOAD_FIELD_LIMIT as u64));
+                }
+                value.extend_from_slice(&chunk);
+            }
+            let value = String::from_utf8(value).map_err(|_| Error::ParamInvalid {
+                got: "<binary>".into(),
+                field: name.clone(),
+                expect: "utf-8 string".into(),
+            })?;
+            fields.push((name, value));
+        }
+    }
+    Ok((filepath, QString::new(fields)))
+}
+
+// Add a file received by upload into library, with metadata from form fields
+pub(super) async fn add_uploaded_media(
+    state: &AppState,
+    library_uuid: Uuid,
+    filepath: &path::Path,
+    params: &QString,
+    msg: ServerMessage,
+) -> Result<ServerMessage> {
+    let opened_libraries = &state.opened_libraries;
+    let filepath = filepath.to_string_lossy().to_string();
+    let kind = match get_param_option::<String>(params, "type")? {
+        Some(v) => v,
+        None => match {
+            let filepath = filepath.clone();
+            opened_libraries
+                .spawn_blocking(move || guess_media_type(filepath))
+                .await?
+        } {
+            Some(v) => v.into(),
+            // file type could not be guessed, so `type` is required
+            None => return Err(Error::NoParam("type".into())),
+        },
+    };
+    let tags = match get_param_option::<String>(params, "tags")? {
+        Some(v) => v
+            .split(',')
+            .filter(|v| !v.is_empty())
+            .map(|v| {
+                v.trim().parse::<Uuid>().map_err(|_| Error::ParamInvalid {
+                    got: v.to_string(),
+                    field: "tags".into(),
+                    expect: "comma separated Uuid".into(),
+                })
+            })
+            .collect::<Result<Vec<_>>>()?,
+        None => vec![],
+    };
+    let series: Option<Uuid> = get_param_option(params, "series")?;
+    let series_no: Option<u64> = get_param_option(params, "series_no")?;
+    let kind = MediaType::from_str(kind.as_str())?;
+    let sub_type = get_param_option(params, "sub_type")?;
+    let type_addition = get_param_option(params, "type_addition")?;
+    let caption = get_param_option(params, "caption")?;
+    let comment = get_param_option(params, "comment")?;
+
+    // media is kept even if tags or series failed, failure is reported as (at, detail)
+    let (id, failure) = write_library!(opened_libraries, library_uuid, lib, {
+        let id = lib.add_media(filepath, kind, sub_type, type_addition, caption, comment)?;
+        for tag in tags.iter() {
+            if let Err(e) = lib.add_tag(id, tag) {
+                return Ok((
                     id,
-                    media: Some(media),
-                    error: None
-                },
-                Err(e) => MediaQueryResult {
+                    Some(("Tag", format!("Failed to add tag `{}` due to {}.", tag, e))),
+                ));
+            }
+        }
+        if let Some(series) = series {
+            if let Err(e) = lib.add_to_series(id, &series, series_no, false) {
+                return Ok((
                     id,
-                    media: None,
-                    error: Some(e.to_string())
-                }
+                    Some((
+                        "Series",
+                        format!("Failed to add to series `{}` due to {}.", series, e),
+                    )),
+                ));
             }
-        }).collect::<Vec<_>>();
+        }
+        Ok((id, None))
+    })?;
+    state.events.publish(Event::MediaAdded {
+        library: library_uuid,
+        media: id,
+    });
+    let msg = msg.with_media(id);
+    Ok(match failure {
+        Some((at, detail)) => {
+            msg.with_single_error_but_partial_success(at, detail, Some(library_uuid), Some(id))
+        }
+        None => msg,
+    })
+}
 
-        Ok(msg.with_format("json").with_serialized_result(&results)?)
-});
+async fn perform_media_upload(
+    library_uuid: Option<Uuid>,
+    state: &AppState,
+    payload: Multipart,
+    msg: ServerMessage,
+) -> Result<ServerMessage> {
+    let library_uuid = library_uuid.ok_or_else(|| Error::NoParam("Library".into()))?;
+    let dir = UploadDir::new().await?;
+    let (filepath, params) = receive_upload(payload, &dir, state.config.upload.max_size).await?;
+    let filepath = filepath.ok_or_else(|| Error::NoParam("file".into()))?;
+    add_uploaded_media(state, library_uuid, &filepath, &params, msg).await
+}
+
+pub fn spec_media_upload() -> RouteSpec {
+    let text = serde_json::json!({"type": "string"});
+    RouteSpec::new("media_upload", "post", "media/upload")
+        .query::<Uuid>("library")
+        .body(
+            "multipart/form-data",
+            serde_json::json!({
+                "type": "object",
+                "required": ["file"],
+                "properties": {
+                    "file": {"type": "string", "format": "binary"},
+                    "type": text,
+                    "sub_type": text,
+                    "type_addition": text,
+                    "caption": text,
+                    "comment": text,
+                    "tags": {"description": "Comma separated Uuid of tags.", "type": "string"},
+                    "series": {"type": "string", "format": "uuid"},
+                    "series_no": {"type": "integer", "format": "int64", "minimum": 0}
+                }
+            }),
+        )
+}
+
+#[post("media/upload")]
+pub async fn media_upload(
+    req: HttpRequest,
+    payload: Multipart,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    let server_msg = match make_server_message("media/upload", &req) {
+        Ok(v) => v,
+        Err(resp) => return resp,
+    };
+    let result = perform_media_upload(
+        server_msg.library,
+        data.get_ref(),
+        payload,
+        server_msg.clone(),
+    )
+    .await;
+    match result {
+        Ok(v) => IntoResponse::into_response(v, &req),
+        Err(e) => make_error_response(server_msg, e),
+    }
+}
 
 register_services!(
     media_get,
     media_add,
+    media_upload,
     media_remove,
     media_update,
     media_query
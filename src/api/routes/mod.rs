@@ -1,14 +1,27 @@
+mod batch;
+mod cached;
+mod events;
+mod jobs;
 mod library;
 mod media;
+mod ndjson;
+mod openapi;
 mod series;
 mod tag;
+mod upload;
 mod utils;
+mod webhook;
 
 pub(crate) use super::super::AppState;
-pub(crate) use super::error::{Error, Result};
-pub(crate) use super::message::{ServerApiStatus, ServerMessage};
+pub(crate) use super::error::{Error, ErrorCode, MediaResult, Result};
+pub(crate) use super::events::Event;
+pub(crate) use super::libraries::OpenedLibraries;
+pub(crate) use super::message::{ApiVersion, ServerApiStatus, ServerMessage, V2_MEDIA_TYPE};
+pub(crate) use cached::Cached;
+pub(crate) use ndjson::NdJson;
+pub(crate) use openapi::RouteSpec;
 use actix_files::HttpRange;
-pub(crate) use actix_web::{get, web, HttpRequest, HttpResponse, Responder};
+pub(crate) use actix_web::{get, web, Either, HttpMessage, HttpRequest, HttpResponse, Responder};
 pub(crate) use qstring::QString;
 use serde::Deserialize;
 use shiromana_rs::library::{LibraryFeatures, LibraryMetadata, LibrarySummary};
@@ -18,7 +31,6 @@ pub(crate) use std::str::FromStr;
 pub(crate) use std::stringify;
 pub(crate) use std::sync::Arc;
 pub(crate) use std::{io, path};
-pub(crate) use tokio::sync::Mutex;
 
 pub fn get_param<T>(params: &QString, key: &str) -> Result<T>
 where
@@ -64,6 +76,216 @@ pub fn get_param_bool(params: &QString, key: &str) -> Result<bool> {
     }
 }
 
+// Serde error of param `key`, with path of the invalid field inside it like `media.kind`
+fn json_param_error(
+    key: &str,
+    got: String,
+    err: serde_path_to_error::Error<serde_json::Error>,
+) -> Error {
+    let field = match err.path().to_string().as_str() {
+        "." => key.to_string(),
+        path if path.starts_with('[') => format!("{}{}", key, path),
+        path => format!("{}.{}", key, path),
+    };
+    Error::ParamInvalid {
+        got,
+        field,
+        expect: err.into_inner().to_string(),
+    }
+}
+
+pub fn param_from_json<T: serde::de::DeserializeOwned>(
+    value: &serde_json::Value,
+    key: &str,
+) -> Result<T> {
+    serde_path_to_error::deserialize(value.clone())
+        .map_err(|e| json_param_error(key, value.to_string(), e))
+}
+
+// Param sent as json string in query
+pub fn param_from_json_str<T: serde::de::DeserializeOwned>(value: &str, key: &str) -> Result<T> {
+    let de = &mut serde_json::Deserializer::from_str(value);
+    serde_path_to_error::deserialize(de).map_err(|e| json_param_error(key, value.to_string(), e))
+}
+
+// Parameter declared in `params(...)` of `generate_api_broker!`, which is
+// extracted before the route runs and described in the openapi document
+pub trait ApiParam: Sized + serde::de::DeserializeOwned {
+    fn from_query(params: &QString, key: &str) -> Result<Self>;
+    fn schema() -> serde_json::Value;
+    fn required() -> bool {
+        true
+    }
+
+    fn from_json(value: &serde_json::Value, key: &str) -> Result<Self> {
+        param_from_json(value, key)
+    }
+}
+
+macro_rules! impl_api_param {
+    ($($typ:ty => $schema:tt),*) => {
+        $(
+            impl ApiParam for $typ {
+                fn from_query(params: &QString, key: &str) -> Result<Self> {
+                    get_param(params, key)
+                }
+
+                fn schema() -> serde_json::Value {
+                    serde_json::json!($schema)
+                }
+            }
+        )*
+    };
+}
+
+impl_api_param!(
+    u32 => {"type": "integer", "format": "int32", "minimum": 0},
+    u64 => {"type": "integer", "format": "int64", "minimum": 0},
+    usize => {"type": "integer", "format": "int64", "minimum": 0},
+    i64 => {"type": "integer", "format": "int64"},
+    String => {"type": "string"},
+    Uuid => {"type": "string", "format": "uuid"}
+);
+
+// Flags are false if not provided
+impl ApiParam for bool {
+    fn from_query(params: &QString, key: &str) -> Result<Self> {
+        get_param_bool(params, key)
+    }
+
+    fn schema() -> serde_json::Value {
+        serde_json::json!({"type": "boolean", "default": false})
+    }
+
+    fn required() -> bool {
+        false
+    }
+}
+
+impl<T: ApiParam> ApiParam for Option<T> {
+    fn from_query(params: &QString, key: &str) -> Result<Self> {
+        match params.has(key) {
+            true => T::from_query(params, key).map(Some),
+            false => Ok(None),
+        }
+    }
+
+    fn from_json(value: &serde_json::Value, key: &str) -> Result<Self> {
+        match value {
+            serde_json::Value::Null => Ok(None),
+            v => T::from_json(v, key).map(Some),
+        }
+    }
+
+    fn schema() -> serde_json::Value {
+        T::schema()
+    }
+
+    fn required() -> bool {
+        false
+    }
+}
+
+// Params of a request, from json body if it is sent with `Content-Type: application/json`,
+// otherwise from query. Library is always from query, where authentication looks for it.
+pub struct RequestParams {
+    query: QString,
+    body: Option<serde_json::Map<String, serde_json::Value>>,
+}
+
+impl RequestParams {
+    // Fields of body must be one of `keys`
+    pub fn new(req: &HttpRequest, body: &[u8], keys: &[&str]) -> Result<Self> {
+        let query = QString::from(req.query_string());
+        if req.content_type() != "application/json" || body.is_empty() {
+            return Ok(Self { query, body: None });
+        }
+        let body = match serde_json::from_slice(body) {
+            Ok(serde_json::Value::Object(v)) => v,
+            Ok(v) => {
+                return Err(Error::ParamInvalid {
+                    got: v.to_string(),
+                    field: "body".into(),
+                    expect: "json object".into(),
+                })
+            }
+            Err(e) => {
+                return Err(Error::ParamInvalid {
+                    got: e.to_string(),
+                    field: "body".into(),
+                    expect: "json object".into(),
+                })
+            }
+        };
+        Self::with_body(query, body, "body", keys)
+    }
+
+    // Params given as a json object only, like operations of `batch`
+    pub fn from_object(
+        body: serde_json::Map<String, serde_json::Value>,
+        field: &str,
+        keys: &[&str],
+    ) -> Result<Self> {
+        Self::with_body(QString::new(Vec::<(String, String)>::new()), body, field, keys)
+    }
+
+    fn with_body(
+        query: QString,
+        body: serde_json::Map<String, serde_json::Value>,
+        field: &str,
+        keys: &[&str],
+    ) -> Result<Self> {
+        let allowed = keys.iter().filter(|v| **v != "library");
+        if let Some(key) = body.keys().find(|k| !allowed.clone().any(|v| v == k)) {
+            return Err(Error::ParamInvalid {
+                got: key.clone(),
+                field: field.into(),
+                expect: format!(
+                    "one of fields: {}",
+                    allowed.cloned().collect::<Vec<_>>().join(", ")
+                ),
+            });
+        }
+        Ok(Self {
+            query,
+            body: Some(body),
+        })
+    }
+
+    pub fn get<T: ApiParam>(&self, key: &str) -> Result<T> {
+        match self.body.as_ref().and_then(|v| v.get(key)) {
+            Some(v) => T::from_json(v, key),
+            None => T::from_query(&self.query, key),
+        }
+    }
+
+    // All params as strings, fields of body override those of query
+    pub fn into_pairs(self) -> Vec<(String, String)> {
+        let mut pairs = self.query.into_pairs();
+        if let Some(body) = self.body {
+            for (key, value) in body {
+                pairs.retain(|(k, _)| k != &key);
+                let value = match value {
+                    serde_json::Value::String(v) => v,
+                    v => v.to_string(),
+                };
+                pairs.push((key, value));
+            }
+        }
+        pairs
+    }
+}
+
+// Query key of a declared parameter, for keys which are keywords of rust like `type`
+macro_rules! param_key {
+    ($param:ident) => {
+        stringify!($param)
+    };
+    ($param:ident $key:literal) => {
+        $key
+    };
+}
+
 macro_rules! expand_or_dash {
     () => {
         _
@@ -76,22 +298,37 @@ macro_rules! expand_or_dash {
 
 pub trait IntoResponse {
     fn into_response(self, req: &HttpRequest) -> HttpResponse;
+    // Successful response in openapi document
+    fn response_spec() -> serde_json::Value;
 }
 
 impl IntoResponse for HttpResponse {
     fn into_response(self, _: &HttpRequest) -> HttpResponse {
         self
     }
+
+    fn response_spec() -> serde_json::Value {
+        serde_json::json!({
+            "description": "Raw content.",
+            "content": {"application/octet-stream": {"schema": {"type": "string", "format": "binary"}}}
+        })
+    }
 }
 
 impl IntoResponse for ServerMessage {
     fn into_response(self, _: &HttpRequest) -> HttpResponse {
-        match self.status {
-            ServerApiStatus::Success | ServerApiStatus::PartialSuccess => {
-                HttpResponse::Ok().body(self.to_json_string())
+        let resp = match (&self.status, self.code) {
+            (ServerApiStatus::Success, _) | (ServerApiStatus::PartialSuccess, _) => {
+                HttpResponse::Ok()
             }
-            ServerApiStatus::Failed => HttpResponse::BadRequest().body(self.to_json_string()),
-        }
+            (ServerApiStatus::Failed, Some(code)) => HttpResponse::build(code.status()),
+            (ServerApiStatus::Failed, None) => HttpResponse::BadRequest(),
+        };
+        message_response(resp, &self)
+    }
+
+    fn response_spec() -> serde_json::Value {
+        openapi::envelope_response("Message of server.")
     }
 }
 
@@ -102,32 +339,139 @@ impl IntoResponse for actix_files::NamedFile {
             Err(e) => HttpResponse::BadRequest().body(format!("Error while opening file: {}", e)),
         }
     }
+
+    fn response_spec() -> serde_json::Value {
+        serde_json::json!({
+            "description": "File content, with type sniffed from content.",
+            "content": {"*/*": {"schema": {"type": "string", "format": "binary"}}}
+        })
+    }
 }
 
+// v2 is chosen by routes under `/api/v2` or header `Accept`
+pub fn api_version(req: &HttpRequest) -> ApiVersion {
+    if let Some(v) = req.app_data::<web::Data<ApiVersion>>() {
+        return *v.get_ref();
+    }
+    match req.headers().get(actix_web::http::header::ACCEPT) {
+        Some(v) if v.to_str().map_or(false, |v| v.contains(V2_MEDIA_TYPE)) => ApiVersion::V2,
+        _ => ApiVersion::V1,
+    }
+}
+
+pub fn message_response(
+    mut resp: actix_web::dev::HttpResponseBuilder,
+    msg: &ServerMessage,
+) -> HttpResponse {
+    if msg.version == ApiVersion::V2 {
+        resp.content_type(V2_MEDIA_TYPE);
+    }
+    resp.body(msg.to_json_string())
+}
+
+// Prepare message for handlers which cannot be generated by `generate_api_broker!`
+pub fn make_server_message(
+    api: &str,
+    req: &HttpRequest,
+) -> std::result::Result<ServerMessage, HttpResponse> {
+    let qs = QString::from(req.query_string());
+    let server_msg = ServerMessage {
+        api: api.to_string(),
+        is_preety: match qs.get("pretty").unwrap_or("true").to_lowercase().as_str() {
+            "false" => false,
+            "true" => true,
+            _ => true,
+        },
+        version: api_version(req),
+        ..ServerMessage::default()
+    };
+    match get_param_option::<Uuid>(&qs, "library") {
+        Ok(library) => Ok(ServerMessage {
+            library,
+            ..server_msg
+        }),
+        Err(e) => Err(message_response(
+            HttpResponse::build(e.code().status()),
+            &server_msg
+                .with_single_error("parameter", e.to_string(), None, None)
+                .with_error_code(e.code(), e.field()),
+        )),
+    }
+}
+
+// `Cache-Control` configured for route replaces the one set by route
+pub fn set_cache_control(
+    resp: &mut HttpResponse,
+    config: &crate::config::ServerConfig,
+    route: &str,
+) {
+    let cacheable =
+        resp.status().is_success() || resp.status() == actix_web::http::StatusCode::NOT_MODIFIED;
+    if let (true, Some(policy)) = (cacheable, config.cache_control.get(route)) {
+        if let Ok(v) = actix_web::http::HeaderValue::from_str(policy) {
+            resp.headers_mut()
+                .insert(actix_web::http::header::CACHE_CONTROL, v);
+        }
+    }
+}
+
+pub fn make_error_response(server_msg: ServerMessage, err: Error) -> HttpResponse {
+    let library = server_msg.library;
+    let media = match err {
+        Error::MediaNotFound(id) => Some(id),
+        _ => server_msg.media,
+    };
+    let code = err.code();
+    message_response(
+        HttpResponse::build(code.status()),
+        &server_msg
+            .with_single_error("action", err.to_string(), library, media)
+            .with_error_code(code, err.field()),
+    )
+}
+
+// Params declared like `params(id: u64, kind = "type": Option<String>)` are
+// extracted from query or json body and passed to body, they are the source of openapi document.
 macro_rules! generate_api_broker {
-    ($name: ident, $method: ident, $route: expr, ($($arg:ident:$typ:ty),*) -> Result<$rt:ty>, $body: block) => {
+    ($name: ident, $method: ident, $route: expr,
+        params($($param:ident $(= $key:literal)?: $pty:ty),*),
+        ($($arg:ident:$typ:ty),*) -> Result<$rt:ty>, $body: block) => {
         generate_api_broker!(
             $name,
             $method,
-            $route, (), (
+            $route, (),
+            params($($param $(= $key)?: $pty),*),
+            (
                 $($arg:$typ),*
             ) -> Result<$rt>,
             $body);
     };
 
-    ($name: ident, $method: ident, $route: expr, ($($path_arg:ident:$path_ty:ty),*),($($arg:ident:$typ:ty),*) -> Result<$rt:ty>, $body: block) => {
+    ($name: ident, $method: ident, $route: expr, ($($path_arg:ident:$path_ty:ty),*),
+        params($($param:ident $(= $key:literal)?: $pty:ty),*),
+        ($($arg:ident:$typ:ty),*) -> Result<$rt:ty>, $body: block) => {
         paste::paste!{
             async fn [<perform_ $name>](
                 $($arg:$typ,)*
                 $($path_arg:$path_ty,)*
+                $($param:$pty,)*
             ) -> Result<$rt>
             $body
+
+            pub fn [<spec_ $name>]() -> RouteSpec {
+                RouteSpec::new(stringify!($name), stringify!($method), $route)
+                    $(.path::<$path_ty>(stringify!($path_arg)))*
+                    $(.query::<$pty>(param_key!($param $($key)?)))*
+                    .json_body()
+                    .response::<$rt>()
+            }
         }
 
         #[$method($route)]
         pub async fn $name(
             req: HttpRequest,
             web::Path(($($path_arg,)*)): web::Path<($($path_ty,)*)>,
+            body: web::Bytes,
             data: web::Data<AppState>,
         ) -> impl Responder {
             let qs = QString::from(req.query_string());
@@ -138,21 +482,23 @@ macro_rules! generate_api_broker {
                     "true" => true,
                     _ => true
                 },
+                version: api_version(&req),
                 ..ServerMessage::default()
             };
             let library_uuid = match qs.get("library") {
                 Some(s) => match s.parse::<Uuid>() {
                     Ok(v) => Some(v),
                     Err(e) => {
-                        return HttpResponse::BadRequest().body(
-                            server_msg.with_single_error(
+                        return message_response(
+                            HttpResponse::build(ErrorCode::ParamInvalid.status()),
+                            &server_msg.with_single_error(
                                 "parameter",
                                 format!(
                                     "Parameter `library` is not a valid Uuid identifier. Err: {}", e
                                 ),
                                 None,
                                 None
-                            ).to_json_string()
+                            ).with_error_code(ErrorCode::ParamInvalid, Some("library"))
                         )
                     }
                 },
@@ -162,21 +508,32 @@ macro_rules! generate_api_broker {
                 library: library_uuid,
                 ..server_msg
             };
+            let request = match RequestParams::new(&req, &body, &[$(param_key!($param $($key)?)),*]) {
+                Ok(v) => v,
+                Err(e) => return make_error_response(server_msg, e)
+            };
+            $(
+                let $param: $pty = match request.get(param_key!($param $($key)?)) {
+                    Ok(v) => v,
+                    Err(e) => return make_error_response(server_msg, e)
+                };
+            )*
 
-            let result = paste::paste!([<perform_ $name>])(library_uuid, &data.opened_libraries, stringify!($name), qs, server_msg.clone(), $($path_arg,)*).await;
+            let result = paste::paste!([<perform_ $name>])(library_uuid, &data.opened_libraries, stringify!($name), qs, server_msg.clone(), data.get_ref(), $($path_arg,)* $($param,)*).await;
 
             match result {
                 Ok(v) => {
-                    IntoResponse::into_response(v, &req)
+                    let mut resp = IntoResponse::into_response(v, &req);
+                    set_cache_control(&mut resp, &data.config, $route);
+                    resp
                 },
-                Err(e) => HttpResponse::BadRequest().body(
-                    server_msg.with_single_error("action", e.to_string(), library_uuid, None).to_json_string()
-                )
+                Err(e) => make_error_response(server_msg, e)
             }
         }
     };
 }
 
+// Every service needs a `spec_` function, which is generated by `generate_api_broker!`
 macro_rules! register_services {
     ( $( $x: ident),* ) => {
         pub fn services(cfg: &mut web::ServiceConfig) {
@@ -184,20 +541,35 @@ macro_rules! register_services {
                 cfg.service($x);
             )*
         }
+
+        pub fn specs() -> Vec<RouteSpec> {
+            paste::paste!(vec![$([<spec_ $x>](),)*])
+        }
     };
 }
 
-macro_rules! take_mutex {
-    ($v:ident, $body: block) => {{
-        let mut $v = $v.lock().await;
-        $body
-    }};
+// Run body on blocking thread with shared lock of library `uuid`.
+// Body moves what it captures and evaluates to a `Result`.
+macro_rules! read_library {
+    ($libs:ident, $uuid:expr, $lib:ident, $body: block) => {
+        $libs.read(&$uuid, move |$lib| $body).await
+    };
+}
+
+// Run body on blocking thread with exclusive lock of library `uuid`.
+// Body moves what it captures and evaluates to a `Result`.
+macro_rules! write_library {
+    ($libs:ident, $uuid:expr, $lib:ident, $body: block) => {
+        $libs.write(&$uuid, move |$lib| $body).await
+    };
 }
 
 pub(crate) use expand_or_dash;
 pub(crate) use generate_api_broker;
+pub(crate) use param_key;
+pub(crate) use read_library;
 pub(crate) use register_services;
-pub(crate) use take_mutex;
+pub(crate) use write_library;
 
 #[derive(serde::Serialize, serde::Deserialize, Clone)]
 struct LibraryInfo {
@@ -213,27 +585,35 @@ struct ServerStatus {
     opened_libraries: Vec<LibraryInfo>,
 }
 
+// Opened libraries of all unless `library` is given
 generate_api_broker!(status, get, "status",
+    params(library: Option<Uuid>),
     (
         library_uuid: Option<Uuid>,
-        opened_libraries: &Arc<Mutex<HashMap<Uuid, Library>>>,
+        opened_libraries: &Arc<OpenedLibraries>,
         action: &str,
         params: QString,
-        msg: ServerMessage
+        msg: ServerMessage,
+        state: &AppState
     ) -> Result<ServerMessage>,
     {
         let mut libs : Vec<LibraryInfo> = vec![];
-        take_mutex!(
-            opened_libraries, {
-                for (k,v) in opened_libraries.iter() {
-                    libs.push(LibraryInfo {
-                        path: v.get_path().clone(),
-                        metadata: v.get_metadata(),
-                        summary: v.get_summary().clone(),
-                    });
-                }
+        let handles = opened_libraries.handles().into_iter();
+        for (uuid, _) in handles.filter(|(uuid, _)| library.map_or(true, |v| &v == uuid)) {
+            let info = read_library!(opened_libraries, uuid, lib, {
+                Ok(LibraryInfo {
+                    path: lib.get_path().clone(),
+                    metadata: lib.get_metadata(),
+                    summary: lib.get_summary().clone(),
+                })
+            });
+            match info {
+                Ok(v) => libs.push(v),
+                // closed meanwhile
+                Err(Error::LibraryNotOpened(_)) => {},
+                Err(e) => return Err(e)
             }
-        );
+        }
         let server_status = ServerStatus {
             server_version: env!("CARGO_PKG_VERSION"),
             shiromana_lib_version: crate::versions::SHIROMANA_RS,
@@ -244,9 +624,31 @@ generate_api_broker!(status, get, "status",
 
 pub fn services(cfg: &mut web::ServiceConfig) {
     cfg.service(status);
+    cfg.service(openapi::openapi_json);
+    cfg.service(openapi::openapi_viewer);
+    batch::services(cfg);
+    events::services(cfg);
+    jobs::services(cfg);
     library::services(cfg);
     media::services(cfg);
     series::services(cfg);
     tag::services(cfg);
+    upload::services(cfg);
     utils::services(cfg);
+    webhook::services(cfg);
+}
+
+pub fn specs() -> Vec<RouteSpec> {
+    let mut specs = vec![spec_status()];
+    specs.extend(batch::specs());
+    specs.extend(events::specs());
+    specs.extend(jobs::specs());
+    specs.extend(library::specs());
+    specs.extend(media::specs());
+    specs.extend(series::specs());
+    specs.extend(tag::specs());
+    specs.extend(upload::specs());
+    specs.extend(utils::specs());
+    specs.extend(webhook::specs());
+    specs
 }
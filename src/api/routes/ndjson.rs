@@ -0,0 +1,104 @@
+use super::*;
+
+use futures::stream::{self, Stream, StreamExt};
+use serde::Serialize;
+
+pub const NDJSON_TYPE: &str = "application/x-ndjson";
+
+// Listing written as one json per line, sent chunked while batches are produced
+pub struct NdJson(HttpResponse);
+
+// Written as the last line when a batch failed, since status is already sent
+#[derive(Serialize)]
+struct ErrorLine {
+    error: String,
+    code: ErrorCode,
+}
+
+fn to_line<T: Serialize>(value: &T, buf: &mut Vec<u8>) -> Result<()> {
+    serde_json::to_writer(&mut *buf, value)?;
+    buf.push(b'\n');
+    Ok(())
+}
+
+fn to_lines<T: Serialize>(batch: Result<Vec<T>>) -> web::Bytes {
+    let mut buf = vec![];
+    let result = batch.and_then(|v| v.iter().try_for_each(|v| to_line(v, &mut buf)));
+    if let Err(e) = result {
+        let line = ErrorLine {
+            error: e.to_string(),
+            code: e.code(),
+        };
+        to_line(&line, &mut buf).ok();
+    }
+    buf.into()
+}
+
+impl NdJson {
+    // Batches after a failed one are not polled
+    pub fn from_batches<S, T>(batches: S) -> Self
+    where
+        S: Stream<Item = Result<Vec<T>>> + 'static,
+        T: Serialize + 'static,
+    {
+        let body = batches
+            .scan(false, |failed, batch| {
+                let end = *failed;
+                *failed = batch.is_err();
+                futures::future::ready(match end {
+                    true => None,
+                    false => Some(Ok::<_, actix_web::Error>(to_lines(batch))),
+                })
+            })
+            .boxed_local();
+        Self(HttpResponse::Ok().content_type(NDJSON_TYPE).streaming(body))
+    }
+
+    // For listings which are already in memory
+    pub fn from_items<T: Serialize + 'static>(items: Vec<T>) -> Self {
+        Self::from_batches(stream::once(futures::future::ready(Ok(items))))
+    }
+
+    pub fn header(mut self, key: &'static str, value: impl ToString) -> Self {
+        if let Ok(value) = value.to_string().parse() {
+            self.0
+                .headers_mut()
+                .insert(actix_web::http::header::HeaderName::from_static(key), value);
+        }
+        self
+    }
+}
+
+impl IntoResponse for NdJson {
+    fn into_response(self, _: &HttpRequest) -> HttpResponse {
+        self.0
+    }
+
+    fn response_spec() -> serde_json::Value {
+        serde_json::json!({
+            "description": "One json per line, the last line has `error` and `code` if failed midway.",
+            "content": {NDJSON_TYPE: {"schema": {"type": "string"}}}
+        })
+    }
+}
+
+// Routes answering with either a message or a ndjson listing
+impl<A: IntoResponse, B: IntoResponse> IntoResponse for actix_web::Either<A, B> {
+    fn into_response(self, req: &HttpRequest) -> HttpResponse {
+        match self {
+            actix_web::Either::A(v) => v.into_response(req),
+            actix_web::Either::B(v) => v.into_response(req),
+        }
+    }
+
+    fn response_spec() -> serde_json::Value {
+        let (mut spec, mut other) = (A::response_spec(), B::response_spec());
+        if let (Some(content), Some(other)) = (
+            spec["content"].as_object_mut(),
+            other["content"].as_object_mut(),
+        ) {
+            content.append(other);
+        }
+        spec
+    }
+}
@@ -0,0 +1,245 @@
+use super::*;
+
+use super::super::auth::QUERY_TOKEN_ROUTES;
+use serde_json::{json, Map, Value};
+use std::collections::BTreeMap;
+
+const ENVELOPE_REF: &str = "#/components/schemas/ServerMessage";
+
+pub struct ParamSpec {
+    name: &'static str,
+    location: &'static str,
+    required: bool,
+    schema: Value,
+}
+
+// Description of a single route, collected into openapi document
+pub struct RouteSpec {
+    operation: &'static str,
+    method: &'static str,
+    path: &'static str,
+    params: Vec<ParamSpec>,
+    body: Option<(&'static str, Value)>,
+    json_body: bool,
+    response: Value,
+    is_envelope: bool,
+}
+
+impl RouteSpec {
+    pub fn new(operation: &'static str, method: &'static str, path: &'static str) -> Self {
+        Self {
+            operation,
+            method,
+            path,
+            params: vec![],
+            body: None,
+            json_body: false,
+            response: envelope_response("Message of server."),
+            is_envelope: true,
+        }
+    }
+
+    pub fn path<T: ApiParam>(mut self, name: &'static str) -> Self {
+        self.params.push(ParamSpec {
+            name,
+            location: "path",
+            required: true,
+            schema: T::schema(),
+        });
+        self
+    }
+
+    pub fn query<T: ApiParam>(mut self, name: &'static str) -> Self {
+        self.params.push(ParamSpec {
+            name,
+            location: "query",
+            required: T::required(),
+            schema: T::schema(),
+        });
+        self
+    }
+
+    pub fn header<T: ApiParam>(mut self, name: &'static str) -> Self {
+        self.params.push(ParamSpec {
+            name,
+            location: "header",
+            required: T::required(),
+            schema: T::schema(),
+        });
+        self
+    }
+
+    pub fn body(mut self, content_type: &'static str, schema: Value) -> Self {
+        self.body = Some((content_type, schema));
+        self
+    }
+
+    // Query params except `library` could also be sent as json body of post
+    pub fn json_body(mut self) -> Self {
+        self.json_body = true;
+        self
+    }
+
+    fn json_body_schema(&self) -> Value {
+        let fields = self
+            .params
+            .iter()
+            .filter(|v| v.location == "query" && v.name != "library");
+        json!({
+            "type": "object",
+            "additionalProperties": false,
+            "required": fields.clone().filter(|v| v.required).map(|v| v.name).collect::<Vec<_>>(),
+            "properties": fields.map(|v| (v.name.to_string(), v.schema.clone())).collect::<Map<_, _>>(),
+        })
+    }
+
+    pub fn response<T: IntoResponse>(mut self) -> Self {
+        self.response = T::response_spec();
+        self.is_envelope = self.response["content"]["application/json"]["schema"]["$ref"]
+            == ENVELOPE_REF;
+        self
+    }
+
+    // For handlers answering with headers only
+    pub fn empty_response(mut self, description: &str) -> Self {
+        self.response = json!({ "description": description });
+        self.is_envelope = false;
+        self
+    }
+
+    fn to_operation(&self) -> Value {
+        let mut params = self
+            .params
+            .iter()
+            .map(|v| {
+                json!({
+                    "name": v.name,
+                    "in": v.location,
+                    "required": v.required,
+                    "schema": v.schema,
+                })
+            })
+            .collect::<Vec<_>>();
+        if self.is_envelope {
+            params.push(json!({ "$ref": "#/components/parameters/pretty" }));
+        }
+        let mut operation = json!({
+            "operationId": self.operation,
+            "parameters": params,
+            "responses": {
+                "200": self.response,
+                "default": envelope_response("Failed message of server."),
+            },
+        });
+        if let Some((content_type, schema)) = &self.body {
+            operation["requestBody"] = json!({
+                "required": true,
+                "content": { *content_type: { "schema": schema } },
+            });
+        } else if self.json_body && self.method == "post" {
+            operation["requestBody"] = json!({
+                "description": "Instead of query params, which are kept for compatibility.",
+                "required": false,
+                "content": { "application/json": { "schema": self.json_body_schema() } },
+            });
+        }
+        operation
+    }
+}
+
+pub fn envelope_response(description: &str) -> Value {
+    json!({
+        "description": description,
+        "content": {"application/json": {"schema": {"$ref": ENVELOPE_REF}}}
+    })
+}
+
+fn envelope_schema() -> Value {
+    json!({
+        "type": "object",
+        "required": ["api", "status"],
+        "properties": {
+            "api": {"type": "string"},
+            "status": {"type": "string", "enum": ["Success", "PartialSuccess", "Failed"]},
+            "error": {
+                "description": "Pairs of where and why it failed.",
+                "type": "array",
+                "items": {
+                    "type": "array",
+                    "items": {"type": "string"},
+                    "minItems": 2,
+                    "maxItems": 2
+                }
+            },
+            "code": {"type": "string", "enum": ErrorCode::ALL},
+            "field": {"description": "Param which caused the error.", "type": "string"},
+            "library": {"type": "string", "format": "uuid"},
+            "media": {"type": "integer", "format": "int64", "minimum": 0},
+            "format": {
+                "description": "How `result` should be decoded, like `json`, `uuid` or `base64`.",
+                "type": "string"
+            },
+            "result": {
+                "description": "String in v1, json results are encoded into it. Any json in v2."
+            },
+            "data": {"type": "object", "additionalProperties": {"type": "string"}}
+        }
+    })
+}
+
+pub fn document(routes: &[RouteSpec]) -> Value {
+    let mut paths: BTreeMap<String, Map<String, Value>> = BTreeMap::new();
+    for route in routes {
+        paths
+            .entry(format!("/{}", route.path))
+            .or_default()
+            .insert(route.method.to_lowercase(), route.to_operation());
+    }
+    for route in QUERY_TOKEN_ROUTES.iter() {
+        for operation in paths.entry(format!("/{}", route)).or_default().values_mut() {
+            operation["security"] = json!([{"bearer": []}, {"access_token": []}]);
+        }
+    }
+    json!({
+        "openapi": "3.0.3",
+        "info": {
+            "title": "Shiromana Server",
+            "version": env!("CARGO_PKG_VERSION"),
+        },
+        "servers": [
+            {"url": "/api", "description": "Result of message is always a string."},
+            {"url": "/api/v2", "description": "Result of message is json."}
+        ],
+        "security": [{"bearer": []}],
+        "paths": paths,
+        "components": {
+            "schemas": {"ServerMessage": envelope_schema()},
+            "parameters": {
+                "pretty": {
+                    "name": "pretty",
+                    "in": "query",
+                    "required": false,
+                    "schema": {"type": "boolean", "default": true}
+                }
+            },
+            "securitySchemes": {
+                "bearer": {"type": "http", "scheme": "bearer"},
+                "access_token": {"type": "apiKey", "in": "query", "name": "access_token"}
+            }
+        }
+    })
+}
+
+#[get("openapi.json")]
+pub async fn openapi_json() -> impl Responder {
+    HttpResponse::Ok()
+        .content_type("application/json")
+        .body(serde_json::to_string_pretty(&document(&specs())).unwrap_or_default())
+}
+
+#[get("docs")]
+pub async fn openapi_viewer() -> impl Responder {
+    HttpResponse::Ok()
+        .content_type("text/html; charset=utf-8")
+        .body(include_str!("openapi.html"))
+}
@@ -1,143 +1,216 @@
 use super::*;
 
+use super::super::query::MediaIds;
+use super::media::{select_medias, BulkResult};
 use actix_web::{get, post};
 use shiromana_rs::library::Library;
+use shiromana_rs::series::Series;
+
+// Bodies of routes below working on a locked library, shared with operations of `batch`
+
+pub(super) fn get_series(lib: &Library, series: &Uuid) -> Result<Series> {
+    Ok(lib.get_series(series)?)
+}
+
+pub(super) fn create_series(
+    lib: &mut Library,
+    caption: String,
+    comment: Option<String>,
+) -> Result<String> {
+    Ok(lib.create_series(caption, comment)?)
+}
+
+pub(super) fn delete_series(lib: &mut Library, series: &Uuid) -> Result<()> {
+    Ok(lib.delete_series(series)?)
+}
+
+// Ids of added medias, with result of each unless a single media is given.
+// Medias given in bulk are numbered from `no` one by one.
+pub(super) fn add_series_media(
+    lib: &mut Library,
+    media: Option<MediaIds>,
+    q: Option<String>,
+    series: &Uuid,
+    no: Option<u64>,
+    unsorted: bool,
+) -> Result<(Vec<u64>, Option<BulkResult>)> {
+    let selected = select_medias(lib, media, q)?;
+    if selected.single {
+        lib.add_to_series(selected.ids[0], series, no, unsorted)?;
+        return Ok((selected.ids, None));
+    }
+    let mut next = no;
+    let result = BulkResult::apply(&selected.ids, |id| {
+        lib.add_to_series(id, series, next, unsorted)?;
+        next = next.map(|v| v + 1);
+        Ok(())
+    });
+    Ok((result.succeeded().to_vec(), Some(result)))
+}
+
+pub(super) fn remove_series_media(lib: &mut Library, media: u64, series: &Uuid) -> Result<()> {
+    Ok(lib.remove_from_series(media, series)?)
+}
+
+pub(super) fn update_series_no(
+    lib: &mut Library,
+    media: u64,
+    series: &Uuid,
+    no: u64,
+    insert: bool,
+) -> Result<()> {
+    Ok(lib.update_series_no(media, series, no, insert)?)
+}
+
+pub(super) fn trim_series_no(lib: &mut Library, series: &Uuid) -> Result<()> {
+    Ok(lib.trim_series_no(series)?)
+}
 
 generate_api_broker!(series_create, post, "series/create",
+    params(library: Uuid, caption: String, comment: Option<String>),
     (
         library_uuid: Option<Uuid>,
-        opened_libraries: &Arc<Mutex<HashMap<Uuid, Library>>>,
+        opened_libraries: &Arc<OpenedLibraries>,
         action: &str,
         params: QString,
-        msg: ServerMessage
+        msg: ServerMessage,
+        state: &AppState
     ) -> Result<ServerMessage>,
     {
-        let library_uuid = library_uuid
-            .ok_or_else(|| Error::NoParam("Library".into()))?;
-        let series = take_mutex!(opened_libraries, {
-            let mut lib = opened_libraries.get_mut(&library_uuid)
-                .ok_or_else(|| Error::LibraryNotOpened(library_uuid))?;
-            lib.create_series(get_param(&params, "caption")?,
-                get_param_option(&params, "comment")?)
+        let series = write_library!(opened_libraries, library, lib, {
+            create_series(lib, caption, comment)
         })?;
+        if let Ok(uuid) = series.parse() {
+            state.events.publish(Event::SeriesCreated { library, series: uuid });
+        }
         Ok(msg.with_result(series).with_format("uuid"))
 });
 
 generate_api_broker!(series_delete, post, "series/delete",
+    params(library: Uuid, series: Uuid),
     (
         library_uuid: Option<Uuid>,
-        opened_libraries: &Arc<Mutex<HashMap<Uuid, Library>>>,
+        opened_libraries: &Arc<OpenedLibraries>,
         action: &str,
         params: QString,
-        msg: ServerMessage
+        msg: ServerMessage,
+        state: &AppState
     ) -> Result<ServerMessage>,
     {
-        let library_uuid = library_uuid
-            .ok_or_else(|| Error::NoParam("Library".into()))?;
-        take_mutex!(opened_libraries, {
-            let mut lib = opened_libraries.get_mut(&library_uuid)
-                .ok_or_else(|| Error::LibraryNotOpened(library_uuid))?;
-            lib.delete_series(&get_param(&params, "series")?)?;
-        });
+        write_library!(opened_libraries, library, lib, {
+            delete_series(lib, &series)
+        })?;
+        state.events.publish(Event::SeriesDeleted { library, series });
         Ok(msg)
 });
 
 generate_api_broker!(series_add_media, post, "series/add_media",
+    params(
+        library: Uuid,
+        media: Option<MediaIds>,
+        q: Option<String>,
+        series: Uuid,
+        no: Option<u64>,
+        unsorted: bool
+    ),
     (
         library_uuid: Option<Uuid>,
-        opened_libraries: &Arc<Mutex<HashMap<Uuid, Library>>>,
+        opened_libraries: &Arc<OpenedLibraries>,
         action: &str,
         params: QString,
-        msg: ServerMessage
+        msg: ServerMessage,
+        state: &AppState
     ) -> Result<ServerMessage>,
     {
-        let library_uuid = library_uuid
-            .ok_or_else(|| Error::NoParam("Library".into()))?;
-        take_mutex!(opened_libraries, {
-            let mut lib = opened_libraries.get_mut(&library_uuid)
-                .ok_or_else(|| Error::LibraryNotOpened(library_uuid))?;
-            lib.add_to_series(
-                get_param(&params, "media")?,
-                &get_param(&params, "series")?,
-                get_param_option(&params, "no")?,
-                get_param_bool(&params, "unsorted")?
-            )?;
-        });
-        Ok(msg)
+        let (added, result) = write_library!(opened_libraries, library, lib, {
+            add_series_media(lib, media, q, &series, no, unsorted)
+        })?;
+        if !added.is_empty() {
+            state.events.publish(Event::SeriesMediaAdded { library, series, media: added });
+        }
+        match result {
+            Some(result) => result.into_message(msg),
+            None => Ok(msg)
+        }
 });
 
 generate_api_broker!(series_remove_media, post, "series/remove_media",
+    params(library: Uuid, media: u64, series: Uuid),
     (
         library_uuid: Option<Uuid>,
-        opened_libraries: &Arc<Mutex<HashMap<Uuid, Library>>>,
+        opened_libraries: &Arc<OpenedLibraries>,
         action: &str,
         params: QString,
-        msg: ServerMessage
+        msg: ServerMessage,
+        state: &AppState
     ) -> Result<ServerMessage>,
     {
-     let library_uuid = library_uuid
-            .ok_or_else(|| Error::NoParam("Library".into()))?;
-        take_mutex!(opened_libraries, {
-            let mut lib = opened_libraries.get_mut(&library_uuid)
-                .ok_or_else(|| Error::LibraryNotOpened(library_uuid))?;
-            lib.remove_from_series(
-                get_param(&params, "media")?,
-                &get_param(&params, "series")?,
-            )?;
-        });
+        write_library!(opened_libraries, library, lib, {
+            remove_series_media(lib, media, &series)
+        })?;
+        state.events.publish(Event::SeriesMediaRemoved { library, series, media: vec![media] });
         Ok(msg)
 });
 
 generate_api_broker!(series_update_no, post, "series/update_no",
+    params(library: Uuid, media: u64, series: Uuid, no: u64, insert: bool),
     (
         library_uuid: Option<Uuid>,
-        opened_libraries: &Arc<Mutex<HashMap<Uuid, Library>>>,
+        opened_libraries: &Arc<OpenedLibraries>,
         action: &str,
         params: QString,
-        msg: ServerMessage
+        msg: ServerMessage,
+        state: &AppState
     ) -> Result<ServerMessage>,
     {
-        let library_uuid = library_uuid
-            .ok_or_else(|| Error::NoParam("Library".into()))?;
-        take_mutex!(opened_libraries, {
-            let mut lib = opened_libraries.get_mut(&library_uuid)
-                .ok_or_else(|| Error::LibraryNotOpened(library_uuid))?;
-            lib.update_series_no(
-                get_param(&params, "media")?,
-                &get_param(&params, "series")?,
-                get_param(&params, "no")?,
-                get_param_bool(&params, "insert")?
-            )?;
-        });
+        write_library!(opened_libraries, library, lib, {
+            update_series_no(lib, media, &series, no, insert)
+        })?;
+        state.events.publish(Event::SeriesReordered { library, series });
         Ok(msg)
 });
 
 generate_api_broker!(series_trim_no, post, "series/trim_no",
+    params(library: Uuid, series: Uuid),
     (
         library_uuid: Option<Uuid>,
-        opened_libraries: &Arc<Mutex<HashMap<Uuid, Library>>>,
+        opened_libraries: &Arc<OpenedLibraries>,
         action: &str,
         params: QString,
-        msg: ServerMessage
+        msg: ServerMessage,
+        state: &AppState
     ) -> Result<ServerMessage>,
     {
-        let library_uuid = library_uuid
-            .ok_or_else(|| Error::NoParam("Library".into()))?;
-        take_mutex!(opened_libraries, {
-            let mut lib = opened_libraries.get_mut(&library_uuid)
-                .ok_or_else(|| Error::LibraryNotOpened(library_uuid))?;
-            lib.trim_series_no(
-                &get_param(&params, "series")?,
-            )?;
-        });
+        write_library!(opened_libraries, library, lib, {
+            trim_series_no(lib, &series)
+        })?;
+        state.events.publish(Event::SeriesReordered { library, series });
         Ok(msg)
 });
 
+generate_api_broker!(series_get, get, "series/get",
+    params(library: Uuid, series: Uuid),
+    (
+        library_uuid: Option<Uuid>,
+        opened_libraries: &Arc<OpenedLibraries>,
+        action: &str,
+        params: QString,
+        msg: ServerMessage,
+        state: &AppState
+    ) -> Result<ServerMessage>,
+    {
+        let series = read_library!(opened_libraries, library, lib, {
+            get_series(lib, &series)
+        })?;
+        Ok(msg.with_serialized_result(&series)?.with_format("json"))
+});
+
 register_services!(
     series_create,
     series_delete,
     series_add_media,
     series_remove_media,
     series_update_no,
-    series_trim_no
+    series_trim_no,
+    series_get
 );
@@ -1,89 +1,149 @@
 use super::*;
 
+use super::super::query::MediaIds;
+use super::media::{select_medias, BulkResult};
 use actix_web::{get, post};
 use shiromana_rs::library::Library;
+use shiromana_rs::tag::Tag;
+
+// Bodies of routes below working on a locked library, shared with operations of `batch`
+
+pub(super) fn get_tag(lib: &Library, tag: &Uuid) -> Result<Tag> {
+    Ok(lib.get_tag(tag)?)
+}
+
+pub(super) fn create_tag(
+    lib: &mut Library,
+    caption: String,
+    comment: Option<String>,
+) -> Result<String> {
+    Ok(lib.create_tag(caption, comment)?)
+}
+
+pub(super) fn delete_tag(lib: &mut Library, tag: Uuid) -> Result<()> {
+    Ok(lib.delete_tag(tag)?)
+}
+
+// Ids of tagged medias, with result of each unless a single media is given
+pub(super) fn add_tag_media(
+    lib: &mut Library,
+    media: Option<MediaIds>,
+    q: Option<String>,
+    tag: &Uuid,
+) -> Result<(Vec<u64>, Option<BulkResult>)> {
+    let selected = select_medias(lib, media, q)?;
+    if selected.single {
+        lib.add_tag(selected.ids[0], tag)?;
+        return Ok((selected.ids, None));
+    }
+    let result = BulkResult::apply(&selected.ids, |id| Ok(lib.add_tag(id, tag)?));
+    Ok((result.succeeded().to_vec(), Some(result)))
+}
+
+pub(super) fn remove_tag_media(lib: &mut Library, media: u64, tag: &Uuid) -> Result<()> {
+    Ok(lib.remove_tag(media, tag)?)
+}
 
 generate_api_broker!(tag_create, post, "tag/create",
+    params(library: Uuid, caption: String, comment: Option<String>),
     (
         library_uuid: Option<Uuid>,
-        opened_libraries: &Arc<Mutex<HashMap<Uuid, Library>>>,
+        opened_libraries: &Arc<OpenedLibraries>,
         action: &str,
         params: QString,
-        msg: ServerMessage
+        msg: ServerMessage,
+        state: &AppState
     ) -> Result<ServerMessage>,
     {
-        let library_uuid = library_uuid
-            .ok_or_else(|| Error::NoParam("Library".into()))?;
-        let tag = take_mutex!(opened_libraries, {
-            let mut lib = opened_libraries.get_mut(&library_uuid)
-                .ok_or_else(|| Error::LibraryNotOpened(library_uuid))?;
-            lib.create_tag(get_param(&params, "caption")?,
-                get_param_option(&params, "comment")?)
+        let tag = write_library!(opened_libraries, library, lib, {
+            create_tag(lib, caption, comment)
         })?;
+        if let Ok(uuid) = tag.parse() {
+            state.events.publish(Event::TagCreated { library, tag: uuid });
+        }
         Ok(msg.with_result(tag).with_format("uuid"))
 });
 
 generate_api_broker!(tag_delete, post, "tag/delete",
+    params(library: Uuid, tag: Uuid),
     (
         library_uuid: Option<Uuid>,
-        opened_libraries: &Arc<Mutex<HashMap<Uuid, Library>>>,
+        opened_libraries: &Arc<OpenedLibraries>,
         action: &str,
         params: QString,
-        msg: ServerMessage
+        msg: ServerMessage,
+        state: &AppState
     ) -> Result<ServerMessage>,
     {
-        let library_uuid = library_uuid
-            .ok_or_else(|| Error::NoParam("Library".into()))?;
-        take_mutex!(opened_libraries, {
-            let mut lib = opened_libraries.get_mut(&library_uuid)
-                .ok_or_else(|| Error::LibraryNotOpened(library_uuid))?;
-            lib.delete_tag(get_param(&params, "tag")?)?;
-        });
+        write_library!(opened_libraries, library, lib, {
+            delete_tag(lib, tag)
+        })?;
+        state.events.publish(Event::TagDeleted { library, tag });
         Ok(msg)
 });
 
 generate_api_broker!(tag_add_media, post, "tag/add_media",
+    params(library: Uuid, media: Option<MediaIds>, q: Option<String>, tag: Uuid),
     (
         library_uuid: Option<Uuid>,
-        opened_libraries: &Arc<Mutex<HashMap<Uuid, Library>>>,
+        opened_libraries: &Arc<OpenedLibraries>,
         action: &str,
         params: QString,
-        msg: ServerMessage
+        msg: ServerMessage,
+        state: &AppState
     ) -> Result<ServerMessage>,
     {
-        let library_uuid = library_uuid
-            .ok_or_else(|| Error::NoParam("Library".into()))?;
-        take_mutex!(opened_libraries, {
-            let mut lib = opened_libraries.get_mut(&library_uuid)
-                .ok_or_else(|| Error::LibraryNotOpened(library_uuid))?;
-            lib.add_tag(
-                get_param(&params, "media")?,
-                &get_param(&params, "tag")?,
-            )?;
-        });
-        Ok(msg)
+        let (added, result) = write_library!(opened_libraries, library, lib, {
+            add_tag_media(lib, media, q, &tag)
+        })?;
+        if !added.is_empty() {
+            state.events.publish(Event::TagMediaAdded { library, tag, media: added });
+        }
+        match result {
+            Some(result) => result.into_message(msg),
+            None => Ok(msg)
+        }
 });
 
 generate_api_broker!(tag_remove_media, post, "tag/remove_media",
+    params(library: Uuid, media: u64, tag: Uuid),
     (
         library_uuid: Option<Uuid>,
-        opened_libraries: &Arc<Mutex<HashMap<Uuid, Library>>>,
+        opened_libraries: &Arc<OpenedLibraries>,
         action: &str,
         params: QString,
-        msg: ServerMessage
+        msg: ServerMessage,
+        state: &AppState
     ) -> Result<ServerMessage>,
     {
-     let library_uuid = library_uuid
-            .ok_or_else(|| Error::NoParam("Library".into()))?;
-        take_mutex!(opened_libraries, {
-            let mut lib = opened_libraries.get_mut(&library_uuid)
-                .ok_or_else(|| Error::LibraryNotOpened(library_uuid))?;
-            lib.remove_tag(
-                get_param(&params, "media")?,
-                &get_param(&params, "tag")?,
-            )?;
-        });
+        write_library!(opened_libraries, library, lib, {
+            remove_tag_media(lib, media, &tag)
+        })?;
+        state.events.publish(Event::TagMediaRemoved { library, tag, media: vec![media] });
         Ok(msg)
 });
 
-register_services!(tag_create, tag_delete, tag_add_media, tag_remove_media);
+generate_api_broker!(tag_get, get, "tag/get",
+    params(library: Uuid, tag: Uuid),
+    (
+        library_uuid: Option<Uuid>,
+        opened_libraries: &Arc<OpenedLibraries>,
+        action: &str,
+        params: QString,
+        msg: ServerMessage,
+        state: &AppState
+    ) -> Result<ServerMessage>,
+    {
+        let tag = read_library!(opened_libraries, library, lib, {
+            get_tag(lib, &tag)
+        })?;
+        Ok(msg.with_serialized_result(&tag)?.with_format("json"))
+});
+
+register_services!(
+    tag_create,
+    tag_delete,
+    tag_add_media,
+    tag_remove_media,
+    tag_get
+);
@@ -0,0 +1,278 @@
+use super::*;
+
+use super::super::auth::check_library;
+use super::super::upload::UploadSession;
+use actix_web::http::header::{self, HeaderName, HeaderValue};
+use actix_web::{post, route};
+
+const UPLOAD_OFFSET: &str = "upload-offset";
+const UPLOAD_LENGTH: &str = "upload-length";
+
+fn with_upload_headers(mut resp: HttpResponse, session: &UploadSession) -> HttpResponse {
+    let headers = resp.headers_mut();
+    headers.insert(
+        HeaderName::from_static(UPLOAD_OFFSET),
+        HeaderValue::from(session.offset),
+    );
+    headers.insert(
+        HeaderName::from_static(UPLOAD_LENGTH),
+        HeaderValue::from(session.length),
+    );
+    headers.insert(header::CACHE_CONTROL, HeaderValue::from_static("no-store"));
+    resp
+}
+
+fn with_session(msg: ServerMessage, session: &UploadSession) -> ServerMessage {
+    let mut data = HashMap::new();
+    data.insert("offset".to_string(), session.offset.to_string());
+    data.insert("length".to_string(), session.length.to_string());
+    msg.with_library(session.library)
+        .with_result(session.id.clone())
+        .with_format("upload")
+        .with_data(data)
+}
+
+// Session carries no library in request, so token is checked against the one of session
+async fn get_session(req: &HttpRequest, state: &AppState, id: &str) -> Result<UploadSession> {
+    let session = state.uploads.get(id).await?;
+    check_library(req, &session.library)?;
+    Ok(session)
+}
+
+// Fields of `media/upload` which are kept for finish, nothing else is saved
+const MEDIA_FIELDS: [&str; 8] = [
+    "type",
+    "sub_type",
+    "type_addition",
+    "caption",
+    "comment",
+    "tags",
+    "series",
+    "series_no",
+];
+
+const UPLOAD_FIELDS: [&str; 11] = [
+    "library",
+    "size",
+    "filename",
+    "type",
+    "sub_type",
+    "type_addition",
+    "caption",
+    "comment",
+    "tags",
+    "series",
+    "series_no",
+];
+
+async fn perform_upload_create(
+    state: &AppState,
+    params: RequestParams,
+    msg: ServerMessage,
+) -> Result<(ServerMessage, UploadSession)> {
+    let library_uuid = msg
+        .library
+        .ok_or_else(|| Error::NoParam("Library".into()))?;
+    if !state.opened_libraries.contains(&library_uuid) {
+        return Err(Error::LibraryNotOpened(library_uuid));
+    }
+    let length: u64 = params.get("size")?;
+    let filename: String = params.get("filename")?;
+    // keep the rest for `media/add` on finish
+    let rest = params
+        .into_pairs()
+        .into_iter()
+        .filter(|(k, _)| MEDIA_FIELDS.contains(&k.as_str()))
+        .collect();
+    let session = state
+        .uploads
+        .create(library_uuid, &filename, length, rest)
+        .await?;
+    Ok((with_session(msg, &session), session))
+}
+
+pub fn spec_upload_create() -> RouteSpec {
+    // the rest are fields of `media/upload`, used on finish
+    RouteSpec::new("upload_create", "post", "upload/create")
+        .query::<Uuid>("library")
+        .query::<u64>("size")
+        .query::<String>("filename")
+        .query::<Option<String>>("type")
+        .query::<Option<String>>("sub_type")
+        .query::<Option<String>>("type_addition")
+        .query::<Option<String>>("caption")
+        .query::<Option<String>>("comment")
+        .query::<Option<String>>("tags")
+        .query::<Option<Uuid>>("series")
+        .query::<Option<u64>>("series_no")
+        .json_body()
+}
+
+#[post("upload/create")]
+pub async fn upload_create(
+    req: HttpRequest,
+    body: web::Bytes,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    let server_msg = match make_server_message("upload/create", &req) {
+        Ok(v) => v,
+        Err(resp) => return resp,
+    };
+    let params = match RequestParams::new(&req, &body, &UPLOAD_FIELDS) {
+        Ok(v) => v,
+        Err(e) => return make_error_response(server_msg, e),
+    };
+    match perform_upload_create(&data, params, server_msg.clone()).await {
+        Ok((msg, session)) => with_upload_headers(msg.into_response(&req), &session),
+        Err(e) => make_error_response(server_msg, e),
+    }
+}
+
+pub fn spec_upload_offset() -> RouteSpec {
+    RouteSpec::new("upload_offset", "head", "upload/{id}")
+        .path::<String>("id")
+        .empty_response("Progress in headers `Upload-Offset` and `Upload-Length`.")
+}
+
+#[route("upload/{id}", method = "HEAD")]
+pub async fn upload_offset(
+    req: HttpRequest,
+    web::Path(id): web::Path<String>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    match get_session(&req, &data, &id).await {
+        Ok(session) => with_upload_headers(HttpResponse::Ok().finish(), &session),
+        Err(Error::NotExisted { .. }) => HttpResponse::NotFound().finish(),
+        Err(Error::Forbidden(_)) => HttpResponse::Forbidden().finish(),
+        Err(_) => HttpResponse::BadRequest().finish(),
+    }
+}
+
+pub fn spec_upload_patch() -> RouteSpec {
+    RouteSpec::new("upload_patch", "patch", "upload/{id}")
+        .path::<String>("id")
+        .header::<Option<u64>>("Upload-Offset")
+        .query::<Option<u64>>("offset")
+        .body(
+            "application/offset+octet-stream",
+            serde_json::json!({"type": "string", "format": "binary"}),
+        )
+}
+
+#[route("upload/{id}", method = "PATCH")]
+pub async fn upload_patch(
+    req: HttpRequest,
+    web::Path(id): web::Path<String>,
+    payload: web::Payload,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    let server_msg = match make_server_message("upload/patch", &req) {
+        Ok(v) => v,
+        Err(resp) => return resp,
+    };
+    let offset = match req.headers().get(UPLOAD_OFFSET) {
+        Some(v) => v
+            .to_str()
+            .ok()
+            .and_then(|v| v.parse::<u64>().ok())
+            .ok_or_else(|| Error::ParamInvalid {
+                got: String::from_utf8_lossy(v.as_bytes()).to_string(),
+                field: "Upload-Offset".into(),
+                expect: "u64".into(),
+            }),
+        None => get_param(&QString::from(req.query_string()), "offset"),
+    };
+    let result = match offset {
+        Ok(offset) => match get_session(&req, &data, &id).await {
+            Ok(_) => data.uploads.append(&id, offset, payload).await,
+            Err(e) => Err(e),
+        },
+        Err(e) => Err(e),
+    };
+    match result {
+        Ok(session) => with_upload_headers(
+            with_session(server_msg, &session).into_response(&req),
+            &session,
+        ),
+        Err(e) => make_error_response(server_msg, e),
+    }
+}
+
+pub fn spec_upload_delete() -> RouteSpec {
+    RouteSpec::new("upload_delete", "delete", "upload/{id}").path::<String>("id")
+}
+
+#[route("upload/{id}", method = "DELETE")]
+pub async fn upload_delete(
+    req: HttpRequest,
+    web::Path(id): web::Path<String>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    let server_msg = match make_server_message("upload/delete", &req) {
+        Ok(v) => v,
+        Err(resp) => return resp,
+    };
+    let result = match get_session(&req, &data, &id).await {
+        Ok(_) => data.uploads.delete(&id).await,
+        Err(e) => Err(e),
+    };
+    match result {
+        Ok(_) => server_msg.into_response(&req),
+        Err(e) => make_error_response(server_msg, e),
+    }
+}
+
+async fn perform_upload_finish(
+    req: &HttpRequest,
+    state: &AppState,
+    id: &str,
+    msg: ServerMessage,
+) -> Result<ServerMessage> {
+    get_session(req, state, id).await?;
+    let (session, guard) = state.uploads.finish(id).await?;
+    let params = QString::new(session.params.clone());
+    let msg = msg.with_library(session.library);
+    let msg = media::add_uploaded_media(
+        state,
+        session.library,
+        &state.uploads.data_path(&session),
+        &params,
+        msg,
+    )
+    .await?;
+    if let ServerApiStatus::Failed = msg.status {
+        // keep the session so client could retry
+        return Ok(msg);
+    }
+    state.uploads.remove(id).await?;
+    drop(guard);
+    Ok(msg)
+}
+
+pub fn spec_upload_finish() -> RouteSpec {
+    RouteSpec::new("upload_finish", "post", "upload/{id}/finish").path::<String>("id")
+}
+
+#[post("upload/{id}/finish")]
+pub async fn upload_finish(
+    req: HttpRequest,
+    web::Path(id): web::Path<String>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    let server_msg = match make_server_message("upload/finish", &req) {
+        Ok(v) => v,
+        Err(resp) => return resp,
+    };
+    match perform_upload_finish(&req, &data, &id, server_msg.clone()).await {
+        Ok(v) => v.into_response(&req),
+        Err(e) => make_error_response(server_msg, e),
+    }
+}
+
+register_services!(
+    upload_create,
+    upload_offset,
+    upload_patch,
+    upload_delete,
+    upload_finish
+);
@@ -2,48 +2,294 @@ use std::io::Read;
 
 use super::*;
 
+use super::super::images::ImageParams;
+use super::super::jobs::JobContext;
+use super::super::query::MediaIds;
+use super::media::select_medias;
 use actix_files::NamedFile;
 use actix_web::{get, post};
+use log::warn;
 use mime;
 use mime_sniffer::MimeTypeSniffer;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
 use shiromana_rs::library::Library;
+use shiromana_rs::media::MediaType;
+use std::time::Duration;
 
+// Failures listed in report of `make_thumbnails`, others are only counted
+const THUMBNAIL_FAILURES_KEPT: usize = 100;
+// Medias made by `make_thumbnails` between saves of where it is
+const THUMBNAIL_CHECKPOINT: usize = 20;
+// Wait before retrying once library operations are full
+const BUSY_RETRY: Duration = Duration::from_secs(1);
+
+async fn make_thumbnail(libs: Arc<OpenedLibraries>, library: Uuid, media: u64) -> Result<Vec<u8>> {
+    let receiver = libs
+        .write(&library, move |lib| Ok(lib.make_thumbnail(media)))
+        .await?;
+    libs.spawn_blocking(move || receiver.recv()?.for_media(media))
+        .await
+}
+
+async fn get_thumbnail(libs: Arc<OpenedLibraries>, library: Uuid, media: u64) -> Result<Vec<u8>> {
+    let receiver = libs
+        .write(&library, move |lib| Ok(lib.get_thumbnail(media)))
+        .await?;
+    libs.spawn_blocking(move || receiver.recv()?.for_media(media))
+        .await
+}
+
+// Thumbnails are in whatever format library wrote them, so type is sniffed
+fn thumbnail_type(buffer: &[u8]) -> mime::Mime {
+    buffer
+        .sniff_mime_type()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(mime::APPLICATION_OCTET_STREAM)
+}
+
+// With `format=raw` thumbnail is sent as body, otherwise in base64 inside message
+fn is_raw(format: Option<String>) -> Result<bool> {
+    match format.as_deref() {
+        None | Some("base64") => Ok(false),
+        Some("raw") => Ok(true),
+        Some(v) => Err(Error::ParamInvalid {
+            got: v.to_string(),
+            field: "format".into(),
+            expect: "base64 or raw".into(),
+        }),
+    }
+}
+
+fn thumbnail_message(
+    msg: ServerMessage,
+    buffer: Vec<u8>,
+    raw: bool,
+) -> Either<ServerMessage, HttpResponse> {
+    let content_type = thumbnail_type(&buffer).to_string();
+    if raw {
+        return Either::B(HttpResponse::Ok().content_type(content_type).body(buffer));
+    }
+    let mut data = HashMap::new();
+    data.insert("content_type".to_string(), content_type);
+    Either::A(
+        msg.with_result(base64::encode(buffer))
+            .with_format("base64")
+            .with_data(data),
+    )
+}
+
+#[derive(Serialize, Default)]
+struct ThumbnailsReport {
+    made: u64,
+    // Medias already having thumbnail, only without `overwrite`
+    skipped: u64,
+    failed: u64,
+    failures: Vec<serde_json::Value>,
+    // Last media handled, pass it as `after` to resume
+    after: Option<u64>,
+}
+
+// Background jobs wait for busy server instead of failing, until they are canceled
+async fn thumbnail_of(
+    ctx: &JobContext,
+    libs: &Arc<OpenedLibraries>,
+    library: Uuid,
+    media: u64,
+    overwrite: bool,
+) -> Result<bool> {
+    loop {
+        let result = match overwrite {
+            true => make_thumbnail(libs.clone(), library, media)
+                .await
+                .map(|_| true),
+            false => match get_thumbnail(libs.clone(), library, media).await {
+                Ok(_) => Ok(false),
+                Err(Error::ServerBusy(n)) => Err(Error::ServerBusy(n)),
+                Err(_) => make_thumbnail(libs.clone(), library, media)
+                    .await
+                    .map(|_| true),
+            },
+        };
+        match result {
+            Err(Error::ServerBusy(_)) if !ctx.is_canceled() => {
+                actix_web::rt::time::delay_for(BUSY_RETRY).await
+            }
+            result => return result,
+        }
+    }
+}
+
+// Selection of `make_thumbnails`, saved with its checkpoint. Ids are kept by digest,
+// as there could be `MEDIA_IDS_LIMIT` of them.
+fn thumbnails_params(
+    media: &Option<MediaIds>,
+    q: &Option<String>,
+    overwrite: bool,
+) -> serde_json::Value {
+    let media = media.as_ref().map(|v| {
+        let bytes: Vec<u8> = v.ids.iter().flat_map(|id| id.to_le_bytes()).collect();
+        Sha256::digest(&bytes)
+            .iter()
+            .map(|v| format!("{:02x}", v))
+            .collect::<String>()
+    });
+    serde_json::json!({"media": media, "q": q, "overwrite": overwrite})
+}
+
+// Failing to save is not worth stopping the job
+async fn save_thumbnails_checkpoint(ctx: &JobContext, after: u64) {
+    if let Err(e) = ctx.checkpoint(serde_json::json!({ "after": after })).await {
+        warn!("Cannot save where job {} is: {}", ctx.id(), e);
+    }
+}
+
+async fn make_thumbnails(
+    ctx: JobContext,
+    libs: Arc<OpenedLibraries>,
+    library: Uuid,
+    ids: Vec<u64>,
+    overwrite: bool,
+) -> Result<serde_json::Value> {
+    let total = ids.len() as u64;
+    let mut report = ThumbnailsReport::default();
+    ctx.progress(0, total);
+    for (done, media) in ids.into_iter().enumerate() {
+        if ctx.is_canceled() {
+            break;
+        }
+        match thumbnail_of(&ctx, &libs, library, media, overwrite).await {
+            // only given up once canceled, media is left to resume
+            Err(Error::ServerBusy(_)) => break,
+            Ok(true) => report.made += 1,
+            Ok(false) => report.skipped += 1,
+            Err(e @ Error::LibraryNotOpened(_)) => return Err(e),
+            Err(e) => {
+                report.failed += 1;
+                if report.failures.len() < THUMBNAIL_FAILURES_KEPT {
+                    report.failures.push(serde_json::json!({
+                        "id": media,
+                        "error": e.to_string(),
+                        "code": e.code(),
+                    }));
+                }
+            }
+        }
+        report.after = Some(media);
+        ctx.progress(done as u64 + 1, total);
+        ctx.report(serde_json::to_value(&report)?);
+        if (done + 1) % THUMBNAIL_CHECKPOINT == 0 {
+            save_thumbnails_checkpoint(&ctx, media).await;
+        }
+    }
+    if let Some(after) = report.after {
+        save_thumbnails_checkpoint(&ctx, after).await;
+    }
+    Ok(serde_json::to_value(&report)?)
+}
+
+// With `background`, thumbnail is made by a job and the job is returned instead
 generate_api_broker!(utils_make_thumbnail, post, "utils/make_thumbnail",
+    params(library: Uuid, media: u64, background: bool, format: Option<String>),
+    (
+        library_uuid: Option<Uuid>,
+        opened_libraries: &Arc<OpenedLibraries>,
+        action: &str,
+        params: QString,
+        msg: ServerMessage,
+        state: &AppState
+    ) -> Result<Either<ServerMessage, HttpResponse>>,
+    {
+        let raw = is_raw(format)?;
+        if background {
+            if raw {
+                return Err(Error::ParamInvalid {
+                    got: "raw".into(),
+                    field: "format".into(),
+                    expect: "base64 with background".into(),
+                });
+            }
+            opened_libraries.get(&library)?;
+            let libs = opened_libraries.clone();
+            let params = serde_json::json!({"media": media});
+            let job = state.jobs.submit(library, "make_thumbnail", params, move |_| async move {
+                let buffer = make_thumbnail(libs, library, media).await?;
+                Ok(serde_json::json!({"media": media, "size": buffer.len()}))
+            });
+            return Ok(Either::A(msg.with_serialized_result(&job)?.with_format("json")));
+        }
+        let buffer = make_thumbnail(opened_libraries.clone(), library, media).await?;
+        Ok(thumbnail_message(msg, buffer, raw))
+});
+
+// Thumbnails of medias selected by `media` or `q`, or of whole library, are made by a job.
+// Medias having thumbnail are skipped unless `overwrite`. Job is resumed from a
+// canceled or failed one by passing `after` of its result, or with `resume` from
+// where the last unfinished one of library with same `media`, `q` and `overwrite`
+// stopped, which survives a restart.
+generate_api_broker!(utils_make_thumbnails, post, "utils/make_thumbnails",
+    params(
+        library: Uuid,
+        media: Option<MediaIds>,
+        q: Option<String>,
+        overwrite: bool,
+        after: Option<u64>,
+        resume: bool
+    ),
     (
         library_uuid: Option<Uuid>,
-        opened_libraries: &Arc<Mutex<HashMap<Uuid, Library>>>,
+        opened_libraries: &Arc<OpenedLibraries>,
         action: &str,
         params: QString,
-        msg: ServerMessage
+        msg: ServerMessage,
+        state: &AppState
     ) -> Result<ServerMessage>,
     {
-        let library_uuid = library_uuid
-            .ok_or_else(|| Error::NoParam("Library".into()))?;
-        let buffer = take_mutex!(opened_libraries, {
-            let mut lib = opened_libraries.get_mut(&library_uuid)
-                .ok_or_else(|| Error::LibraryNotOpened(library_uuid))?;
-            lib.make_thumbnail(get_param(&params, "media")?)
-        }).recv()??;
-        Ok(msg.with_result(base64::encode(buffer)).with_format("base64"))
+        let job_params = thumbnails_params(&media, &q, overwrite);
+        let after = match (after, resume) {
+            (Some(v), true) => return Err(Error::ParamInvalid {
+                got: v.to_string(),
+                field: "after".into(),
+                expect: "either after or resume".into(),
+            }),
+            (None, true) => state
+                .jobs
+                .checkpoint(&library, "make_thumbnails", &job_params)
+                .and_then(|v| v["after"].as_u64()),
+            (after, false) => after,
+        };
+        let ids = read_library!(opened_libraries, library, lib, {
+            let mut ids = match (media, q) {
+                // empty query matches every media
+                (None, None) => lib.query_media("")?,
+                (media, q) => select_medias(lib, media, q)?.ids,
+            };
+            ids.sort_unstable();
+            ids.dedup();
+            Ok(ids)
+        })?;
+        let ids = ids.into_iter().filter(|&id| after.map_or(true, |v| id > v)).collect();
+        let libs = opened_libraries.clone();
+        let job = state.jobs.submit(library, "make_thumbnails", job_params, move |ctx| {
+            make_thumbnails(ctx, libs, library, ids, overwrite)
+        });
+        Ok(msg.with_serialized_result(&job)?.with_format("json"))
 });
 
 generate_api_broker!(utils_get_thumbnail, get, "utils/get_thumbnail",
+    params(library: Uuid, media: u64, format: Option<String>),
     (
         library_uuid: Option<Uuid>,
-        opened_libraries: &Arc<Mutex<HashMap<Uuid, Library>>>,
+        opened_libraries: &Arc<OpenedLibraries>,
         action: &str,
         params: QString,
-        msg: ServerMessage
-    ) -> Result<ServerMessage>,
+        msg: ServerMessage,
+        state: &AppState
+    ) -> Result<Either<ServerMessage, HttpResponse>>,
     {
-        let library_uuid = library_uuid
-            .ok_or_else(|| Error::NoParam("Library".into()))?;
-        let buffer = take_mutex!(opened_libraries, {
-            let mut lib = opened_libraries.get_mut(&library_uuid)
-                .ok_or_else(|| Error::LibraryNotOpened(library_uuid))?;
-            lib.get_thumbnail(get_param(&params, "media")?)
-        }).recv()??;
-        Ok(msg.with_result(base64::encode(buffer)).with_format("base64"))
+        let raw = is_raw(format)?;
+        let buffer = get_thumbnail(opened_libraries.clone(), library, media).await?;
+        Ok(thumbnail_message(msg, buffer, raw))
 });
 
 generate_api_broker!(utils_get_thumbnail_b, get, "{lib}/{media}/thumbnail",
@@ -51,20 +297,25 @@ generate_api_broker!(utils_get_thumbnail_b, get, "{lib}/{media}/thumbnail",
         lib: Uuid,
         media: u64
     ),
+    params(),
     (
         library_uuid: Option<Uuid>,
-        opened_libraries: &Arc<Mutex<HashMap<Uuid, Library>>>,
+        opened_libraries: &Arc<OpenedLibraries>,
         action: &str,
         params: QString,
-        msg: ServerMessage
-    ) -> Result<HttpResponse>,
+        msg: ServerMessage,
+        state: &AppState
+    ) -> Result<Cached<HttpResponse>>,
     {
-        let buffer = take_mutex!(opened_libraries, {
-            let mut lib = opened_libraries.get_mut(&lib)
-                .ok_or_else(|| Error::LibraryNotOpened(lib))?;
-            lib.get_thumbnail(media)
-        }).recv()??;
-        Ok(HttpResponse::Ok().body(buffer))
+        let buffer = get_thumbnail(opened_libraries.clone(), lib, media).await?;
+        let resp = HttpResponse::Ok()
+            .content_type(thumbnail_type(&buffer).to_string())
+            .header(
+                actix_web::http::header::CACHE_CONTROL,
+                format!("max-age={}", state.config.thumbnail.cache_max_age)
+            )
+            .body(buffer.clone());
+        Ok(Cached::of_content(resp, &buffer))
 });
 
 generate_api_broker!(utils_get_media_b, get, "{lib}/{media}/media",
@@ -72,40 +323,102 @@ generate_api_broker!(utils_get_media_b, get, "{lib}/{media}/media",
         lib: Uuid,
         media: u64
     ),
+    params(),
     (
         library_uuid: Option<Uuid>,
-        opened_libraries: &Arc<Mutex<HashMap<Uuid, Library>>>,
+        opened_libraries: &Arc<OpenedLibraries>,
         action: &str,
         params: QString,
-        msg: ServerMessage
-    ) -> Result<NamedFile>,
+        msg: ServerMessage,
+        state: &AppState
+    ) -> Result<Cached<NamedFile>>,
     {
-        let media = take_mutex!(opened_libraries, {
-            let mut lib = opened_libraries.get_mut(&lib)
-                .ok_or_else(|| Error::LibraryNotOpened(lib))?;
-            lib.get_media(media)?
-        });
+        let media = read_library!(opened_libraries, lib, lib, {
+            lib.get_media(media).for_media(media)
+        })?;
         let filepath = media.filepath;
+        let hash = media.hash;
 
-        let mut file = std::fs::File::open(&filepath)?;
-        let mut buffer = [0; 64]; // 64 bytes is enough I guess
-        file.read(&mut buffer)?;
-        let mime_type = match buffer.sniff_mime_type() {
-            Some(s) => Some(s.parse::<mime::Mime>().unwrap()),
-            None => None
-        };
+        opened_libraries.spawn_blocking(move || {
+            let mut file = std::fs::File::open(&filepath)?;
+            let mut buffer = [0; 64]; // 64 bytes is enough I guess
+            file.read(&mut buffer)?;
+            let mime_type = match buffer.sniff_mime_type() {
+                Some(s) => Some(s.parse::<mime::Mime>().unwrap()),
+                None => None
+            };
 
-        let f = NamedFile::open(filepath)?.disable_content_disposition();
-        let f = match mime_type {
-            Some(t) => f.set_content_type(t),
-            None => f
-        };
-        Ok(f)
+            let modified = file.metadata()?.modified()?;
+
+            // validators are derived from hash of media instead of inode and size
+            let f = NamedFile::open(filepath)?
+                .disable_content_disposition()
+                .use_etag(false)
+                .use_last_modified(false);
+            let f = match mime_type {
+                Some(t) => f.set_content_type(t),
+                None => f
+            };
+            Ok(Cached::new(f, &hash).last_modified(modified))
+        }).await
+});
+
+// Image media resized and converted, results are cached on disk.
+// `quality` only applies to jpeg, it is refused with `format` png or webp.
+generate_api_broker!(utils_get_image_b, get, "{lib}/{media}/image",
+    (
+        lib: Uuid,
+        media: u64
+    ),
+    params(
+        w: Option<u32>,
+        h: Option<u32>,
+        fit: Option<String>,
+        format: Option<String>,
+        quality: Option<u32>
+    ),
+    (
+        library_uuid: Option<Uuid>,
+        opened_libraries: &Arc<OpenedLibraries>,
+        action: &str,
+        params: QString,
+        msg: ServerMessage,
+        state: &AppState
+    ) -> Result<Cached<HttpResponse>>,
+    {
+        let image = ImageParams::new(&state.config.image, w, h, fit, format, quality)?;
+        let media = read_library!(opened_libraries, lib, lib, {
+            lib.get_media(media).for_media(media)
+        })?;
+        if !matches!(media.kind, MediaType::Image) {
+            return Err(Error::ParamInvalid {
+                got: media.id.to_string(),
+                field: "media".into(),
+                expect: "image media".into(),
+            });
+        }
+        let key = image.key(&media.hash);
+        let images = state.images.clone();
+        let cache_key = key.clone();
+        let (buffer, format) = opened_libraries.spawn_blocking(move || {
+            if let Some(cached) = images.get(&cache_key) {
+                return Ok(cached);
+            }
+            let (buffer, format) = image.render(&media.filepath)?;
+            if let Err(e) = images.put(&cache_key, format, &buffer) {
+                warn!("Cannot cache resized image of media {}: {}", media.id, e);
+            }
+            Ok((buffer, format))
+        }).await?;
+        let resp = HttpResponse::Ok().content_type(format.content_type()).body(buffer);
+        Ok(Cached::new(resp, &key))
 });
 
 register_services!(
     utils_make_thumbnail,
+    utils_make_thumbnails,
     utils_get_thumbnail,
     utils_get_thumbnail_b,
-    utils_get_media_b
+    utils_get_media_b,
+    utils_get_image_b
 );
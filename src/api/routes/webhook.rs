@@ -0,0 +1,82 @@
+use super::*;
+
+use actix_web::{get, post};
+
+// Deliveries returned by `webhook/deliveries` if no limit given
+const DELIVERIES_LIMIT: usize = 100;
+
+generate_api_broker!(webhook_create, post, "webhook/create",
+    params(library: Uuid, url: String, events: Option<String>, secret: String),
+    (
+        library_uuid: Option<Uuid>,
+        opened_libraries: &Arc<OpenedLibraries>,
+        action: &str,
+        params: QString,
+        msg: ServerMessage,
+        state: &AppState
+    ) -> Result<ServerMessage>,
+    {
+        opened_libraries.get(&library)?;
+        let events = events
+            .unwrap_or_default()
+            .split(',')
+            .map(|v| v.trim().to_string())
+            .filter(|v| !v.is_empty())
+            .collect();
+        let webhook = state.webhooks.create(library, url, events, secret).await?;
+        Ok(msg.with_serialized_result(&webhook)?.with_format("json"))
+});
+
+generate_api_broker!(webhook_list, get, "webhook/list",
+    params(library: Uuid),
+    (
+        library_uuid: Option<Uuid>,
+        opened_libraries: &Arc<OpenedLibraries>,
+        action: &str,
+        params: QString,
+        msg: ServerMessage,
+        state: &AppState
+    ) -> Result<ServerMessage>,
+    {
+        Ok(msg.with_serialized_result(&state.webhooks.list(&library))?.with_format("json"))
+});
+
+generate_api_broker!(webhook_delete, post, "webhook/delete",
+    params(library: Uuid, webhook: u64),
+    (
+        library_uuid: Option<Uuid>,
+        opened_libraries: &Arc<OpenedLibraries>,
+        action: &str,
+        params: QString,
+        msg: ServerMessage,
+        state: &AppState
+    ) -> Result<ServerMessage>,
+    {
+        if !state.webhooks.delete(&library, webhook).await? {
+            return Err(Error::NotExisted {
+                got: webhook.to_string(),
+                field: "webhook".into(),
+                expect: format!("webhook of library {}", library),
+            });
+        }
+        Ok(msg)
+});
+
+generate_api_broker!(webhook_deliveries, get, "webhook/deliveries",
+    params(library: Uuid, webhook: Option<u64>, limit: Option<usize>),
+    (
+        library_uuid: Option<Uuid>,
+        opened_libraries: &Arc<OpenedLibraries>,
+        action: &str,
+        params: QString,
+        msg: ServerMessage,
+        state: &AppState
+    ) -> Result<ServerMessage>,
+    {
+        let deliveries = state.webhooks.deliveries(
+            &library, webhook, limit.unwrap_or(DELIVERIES_LIMIT)
+        );
+        Ok(msg.with_serialized_result(&deliveries)?.with_format("json"))
+});
+
+register_services!(webhook_create, webhook_list, webhook_delete, webhook_deliveries);
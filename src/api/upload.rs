@@ -0,0 +1,325 @@
+use super::error::{Error, Result};
+use super::libraries::block;
+use actix_web::web::Bytes;
+use futures::{Stream, StreamExt};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use shiromana_rs::misc::Uuid;
+use std::collections::HashSet;
+use std::io::prelude::*;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|v| v.as_secs())
+        .unwrap_or(0)
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct UploadSession {
+    pub id: String,
+    pub library: Uuid,
+    pub filename: String,
+    pub length: u64,
+    pub offset: u64,
+    // Params passed to `Library::add_media` when session is finished
+    pub params: Vec<(String, String)>,
+    pub created_at: u64,
+    pub updated_at: u64,
+}
+
+// Resumable upload sessions. Every session is stored on disk as `{id}.json`
+// with its data in `{id}/{filename}`, so they survive a restart of server.
+pub struct UploadStore {
+    dir: PathBuf,
+    timeout: Duration,
+    max_size: u64,
+    busy: Mutex<HashSet<String>>,
+}
+
+// Mark a session as being written, released on drop
+pub struct SessionGuard<'a> {
+    store: &'a UploadStore,
+    id: String,
+}
+
+impl Drop for SessionGuard<'_> {
+    fn drop(&mut self) {
+        self.store.busy.lock().unwrap().remove(&self.id);
+    }
+}
+
+impl UploadStore {
+    pub fn new<P: Into<PathBuf>>(dir: P, timeout: Duration, max_size: u64) -> Result<Self> {
+        let dir = dir.into();
+        std::fs::create_dir_all(&dir)?;
+        Ok(Self {
+            dir,
+            timeout,
+            max_size,
+            busy: Mutex::new(HashSet::new()),
+        })
+    }
+
+    fn check_id(id: &str) -> Result<()> {
+        if id.is_empty() || !id.chars().all(|c| c.is_ascii_alphanumeric() || c == '-') {
+            return Err(Error::ParamInvalid {
+                got: id.to_string(),
+                field: "upload".into(),
+                expect: "upload session id".into(),
+            });
+        }
+        Ok(())
+    }
+
+    fn meta_path(&self, id: &str) -> PathBuf {
+        self.dir.join(format!("{}.json", id))
+    }
+
+    fn data_dir(&self, id: &str) -> PathBuf {
+        self.dir.join(id)
+    }
+
+    pub fn data_path(&self, session: &UploadSession) -> PathBuf {
+        self.data_dir(&session.id).join(&session.filename)
+    }
+
+    fn lock(&self, id: &str) -> Result<SessionGuard> {
+        let mut busy = self.busy.lock().unwrap();
+        if !busy.insert(id.to_string()) {
+            return Err(Error::UploadError(format!(
+                "Upload session `{}` is being written by another request.",
+                id
+            )));
+        }
+        Ok(SessionGuard {
+            store: self,
+            id: id.to_string(),
+        })
+    }
+
+    pub async fn create(
+        &self,
+        library: Uuid,
+        filename: &str,
+        length: u64,
+        params: Vec<(String, String)>,
+    ) -> Result<UploadSession> {
+        if length > self.max_size {
+            return Err(Error::PayloadTooLarge(self.max_size));
+        }
+        let filename = Path::new(filename)
+            .file_name()
+            .ok_or_else(|| Error::ParamInvalid {
+                got: filename.to_string(),
+                field: "filename".into(),
+                expect: "file name".into(),
+            })?
+            .to_string_lossy()
+            .to_string();
+        let time = now();
+        let session = UploadSession {
+            // random, as id is all needed to write into the session
+            id: uuid::Uuid::new_v4().to_string(),
+            library,
+            filename,
+            length,
+            offset: 0,
+            params,
+            created_at: time,
+            updated_at: time,
+        };
+        let (dir, data_dir, data_path) = (
+            self.dir.clone(),
+            self.data_dir(&session.id),
+            self.data_path(&session),
+        );
+        block(move || {
+            std::fs::create_dir_all(data_dir)?;
+            std::fs::File::create(data_path)?;
+            save(&dir, &session)?;
+            Ok(session)
+        })
+        .await
+    }
+
+    pub async fn get(&self, id: &str) -> Result<UploadSession> {
+        Self::check_id(id)?;
+        let (meta, id) = (self.meta_path(id), id.to_string());
+        block(move || load(&meta, &id)).await
+    }
+
+    // Append chunk at `offset`, which must be the current offset of session
+    pub async fn append<S, E>(&self, id: &str, offset: u64, mut chunk: S) -> Result<UploadSession>
+    where
+        S: Stream<Item = std::result::Result<Bytes, E>> + Unpin,
+        E: std::fmt::Display,
+    {
+        Self::check_id(id)?;
+        let _guard = self.lock(id)?;
+        let mut session = self.get(id).await?;
+        if offset != session.offset {
+            return Err(Error::OffsetMismatch {
+                got: offset,
+                expect: session.offset,
+            });
+        }
+        let data_path = self.data_path(&session);
+        let start = session.offset;
+        let mut file = block(move || {
+            let mut file = std::fs::OpenOptions::new().write(true).open(data_path)?;
+            // drop data left by an interrupted request
+            file.set_len(start)?;
+            file.seek(std::io::SeekFrom::Start(start))?;
+            Ok(file)
+        })
+        .await?;
+        let mut result = Ok(());
+        while let Some(bytes) = chunk.next().await {
+            let bytes = match bytes {
+                Ok(v) => v,
+                Err(e) => {
+                    result = Err(Error::UploadError(e.to_string()));
+                    break;
+                }
+            };
+            if session.offset + bytes.len() as u64 > session.length {
+                result = Err(Error::PayloadTooLarge(session.length));
+                break;
+            }
+            let length = bytes.len() as u64;
+            // file is moved into the blocking task and back, as it outlives the task
+            let (returned, written) = block(move || {
+                let written = file.write_all(&bytes);
+                Ok((file, written))
+            })
+            .await?;
+            file = returned;
+            if let Err(e) = written {
+                result = Err(e.into());
+                break;
+            }
+            session.offset += length;
+        }
+        // keep what was received even if connection dropped, so client can resume
+        session.updated_at = now();
+        let dir = self.dir.clone();
+        let session = block(move || {
+            file.sync_data()?;
+            file.set_len(session.offset)?;
+            save(&dir, &session)?;
+            Ok(session)
+        })
+        .await?;
+        result.map(|_| session)
+    }
+
+    // Lock a completed session for adding it into library
+    pub async fn finish(&self, id: &str) -> Result<(UploadSession, SessionGuard<'_>)> {
+        Self::check_id(id)?;
+        let guard = self.lock(id)?;
+        let session = self.get(id).await?;
+        if session.offset != session.length {
+            return Err(Error::UploadError(format!(
+                "Upload session `{}` is not completed, {} of {} bytes received.",
+                id, session.offset, session.length
+            )));
+        }
+        Ok((session, guard))
+    }
+
+    // Remove a session which is not being written
+    pub async fn delete(&self, id: &str) -> Result<()> {
+        Self::check_id(id)?;
+        let _guard = self.lock(id)?;
+        self.get(id).await?;
+        self.remove(id).await
+    }
+
+    pub async fn remove(&self, id: &str) -> Result<()> {
+        Self::check_id(id)?;
+        let (meta, data_dir) = (self.meta_path(id), self.data_dir(id));
+        block(move || remove(&meta, &data_dir)).await
+    }
+
+    // Remove sessions untouched for longer than timeout, returns count of removed.
+    // It is blocking, so should be run on blocking thread pool.
+    pub fn collect_garbage(&self) -> usize {
+        let entries = match std::fs::read_dir(&self.dir) {
+            Ok(v) => v,
+            Err(e) => {
+                warn!("Cannot read upload folder {:?}: {}", self.dir, e);
+                return 0;
+            }
+        };
+        let deadline = now().saturating_sub(self.timeout.as_secs());
+        let mut removed = 0;
+        for entry in entries.filter_map(|v| v.ok()) {
+            let path = entry.path();
+            if path.is_dir() {
+                // data left without metadata
+                let id = entry.file_name().to_string_lossy().to_string();
+                if !self.meta_path(&id).exists() && !self.busy.lock().unwrap().contains(&id) {
+                    let _ = std::fs::remove_dir_all(&path);
+                }
+                continue;
+            }
+            if path.extension().map(|v| v != "json").unwrap_or(true) {
+                continue;
+            }
+            let id = match path.file_stem() {
+                Some(v) => v.to_string_lossy().to_string(),
+                None => continue,
+            };
+            let _guard = match self.lock(&id) {
+                Ok(v) => v,
+                Err(_) => continue,
+            };
+            let expired = match load(&path, &id) {
+                Ok(session) => session.updated_at < deadline,
+                Err(_) => true, // broken metadata
+            };
+            if expired {
+                match remove(&path, &self.data_dir(&id)) {
+                    Ok(_) => removed += 1,
+                    Err(e) => warn!("Cannot remove upload session {}: {}", id, e),
+                }
+            }
+        }
+        if removed > 0 {
+            info!("Removed {} expired upload sessions.", removed);
+        }
+        removed
+    }
+}
+
+// Functions below are blocking, run them with `block`
+
+fn load(meta: &Path, id: &str) -> Result<UploadSession> {
+    if !meta.is_file() {
+        return Err(Error::NotExisted {
+            got: id.to_string(),
+            field: "upload".into(),
+            expect: "upload session".into(),
+        });
+    }
+    Ok(serde_json::from_slice(&std::fs::read(meta)?)?)
+}
+
+fn save(dir: &Path, session: &UploadSession) -> Result<()> {
+    let tmp = dir.join(format!("{}.json.tmp", session.id));
+    std::fs::write(&tmp, serde_json::to_vec(session)?)?;
+    std::fs::rename(tmp, dir.join(format!("{}.json", session.id)))?;
+    Ok(())
+}
+
+fn remove(meta: &Path, data_dir: &Path) -> Result<()> {
+    if data_dir.is_dir() {
+        std::fs::remove_dir_all(data_dir)?;
+    }
+    std::fs::remove_file(meta)?;
+    Ok(())
+}
@@ -0,0 +1,513 @@
+use super::error::{Error, Result};
+use super::events::{EventHub, EVENT_TYPES};
+use super::libraries::block;
+use crate::config::WebhookConfig;
+use actix_web::client::Client;
+use hmac::{Hmac, Mac};
+use log::warn;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use shiromana_rs::misc::Uuid;
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::broadcast::error::RecvError;
+
+// Hex of HMAC-SHA256 of body with secret of webhook, like `sha256=<hex>`
+pub const SIGNATURE_HEADER: &str = "X-Shiromana-Signature";
+const EVENT_HEADER: &str = "X-Shiromana-Event";
+const DELIVERY_HEADER: &str = "X-Shiromana-Delivery";
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|v| v.as_secs())
+        .unwrap_or(0)
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct WebhookInfo {
+    pub id: u64,
+    pub library: Uuid,
+    pub url: String,
+    // Types of events sent to url, all if empty
+    pub events: Vec<String>,
+    pub created_at: u64,
+}
+
+// Secret is never sent back through apis
+#[derive(Serialize, Deserialize, Clone)]
+struct Webhook {
+    #[serde(flatten)]
+    info: WebhookInfo,
+    secret: String,
+}
+
+#[derive(Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum DeliveryStatus {
+    // Not delivered yet, could be waiting for retry
+    Pending,
+    Delivered,
+    // Given up after all attempts failed
+    Failed,
+}
+
+#[derive(Serialize, Clone)]
+pub struct Delivery {
+    pub id: u64,
+    pub webhook: u64,
+    pub event: String,
+    pub status: DeliveryStatus,
+    pub attempts: u32,
+    // Status of the last answer of receiver
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub response_status: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    pub created_at: u64,
+    pub updated_at: u64,
+}
+
+// Saved with the next id, so ids of deleted webhooks are never given again
+#[derive(Serialize, Deserialize, Default)]
+struct Webhooks {
+    next_id: u64,
+    webhooks: Vec<Webhook>,
+}
+
+// Webhooks registered through apis with log of recent deliveries.
+// Webhooks are saved into a json file, deliveries are only kept in memory.
+pub struct WebhookStore {
+    config: WebhookConfig,
+    webhooks: Mutex<Webhooks>,
+    deliveries: Mutex<HashMap<Uuid, VecDeque<Delivery>>>,
+    next_delivery: AtomicU64,
+}
+
+impl WebhookStore {
+    // Load webhooks from `file` of config, which is created if not existed
+    pub fn load(config: &WebhookConfig) -> Result<Self> {
+        let webhooks = if config.file.exists() {
+            serde_json::from_slice(&std::fs::read(&config.file)?)?
+        } else {
+            Webhooks {
+                next_id: 1,
+                webhooks: vec![],
+            }
+        };
+        Ok(Self {
+            config: config.clone(),
+            webhooks: Mutex::new(webhooks),
+            deliveries: Mutex::new(HashMap::new()),
+            next_delivery: AtomicU64::new(1),
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.webhooks.lock().unwrap().webhooks.len()
+    }
+
+    // Written on the blocking thread pool, locked through writing so saves never interleave
+    async fn save(self: &Arc<Self>) -> Result<()> {
+        let store = self.clone();
+        block(move || {
+            let webhooks = store.webhooks.lock().unwrap();
+            let tmp = store.config.file.with_extension("json.tmp");
+            std::fs::write(&tmp, serde_json::to_vec_pretty(&*webhooks)?)?;
+            std::fs::rename(tmp, &store.config.file)?;
+            Ok(())
+        })
+        .await
+    }
+
+    pub async fn create(
+        self: &Arc<Self>,
+        library: Uuid,
+        url: String,
+        events: Vec<String>,
+        secret: String,
+    ) -> Result<WebhookInfo> {
+        let valid_url = url
+            .parse::<actix_web::http::Uri>()
+            .map(|v| matches!(v.scheme_str(), Some("http") | Some("https")) && v.host().is_some())
+            .unwrap_or(false);
+        if !valid_url {
+            return Err(Error::ParamInvalid {
+                got: url,
+                field: "url".into(),
+                expect: "http or https url".into(),
+            });
+        }
+        if let Some(event) = events.iter().find(|v| !EVENT_TYPES.contains(&v.as_str())) {
+            return Err(Error::ParamInvalid {
+                got: event.clone(),
+                field: "events".into(),
+                expect: format!("comma separated events of {}", EVENT_TYPES.join(", ")),
+            });
+        }
+        if secret.is_empty() {
+            return Err(Error::ParamInvalid {
+                got: secret,
+                field: "secret".into(),
+                expect: "non-empty string".into(),
+            });
+        }
+        let info = {
+            let mut webhooks = self.webhooks.lock().unwrap();
+            let info = WebhookInfo {
+                id: webhooks.next_id,
+                library,
+                url,
+                events,
+                created_at: now(),
+            };
+            webhooks.next_id += 1;
+            webhooks.webhooks.push(Webhook {
+                info: info.clone(),
+                secret,
+            });
+            info
+        };
+        self.save().await?;
+        Ok(info)
+    }
+
+    pub fn list(&self, library: &Uuid) -> Vec<WebhookInfo> {
+        self.webhooks
+            .lock()
+            .unwrap()
+            .webhooks
+            .iter()
+            .filter(|v| &v.info.library == library)
+            .map(|v| v.info.clone())
+            .collect()
+    }
+
+    // Returns false if webhook does not belong to library
+    pub async fn delete(self: &Arc<Self>, library: &Uuid, id: u64) -> Result<bool> {
+        {
+            let mut webhooks = self.webhooks.lock().unwrap();
+            let count = webhooks.webhooks.len();
+            webhooks
+                .webhooks
+                .retain(|v| !(v.info.id == id && &v.info.library == library));
+            if webhooks.webhooks.len() == count {
+                return Ok(false);
+            }
+        }
+        self.save().await?;
+        Ok(true)
+    }
+
+    fn get(&self, id: u64) -> Option<Webhook> {
+        self.webhooks
+            .lock()
+            .unwrap()
+            .webhooks
+            .iter()
+            .find(|v| v.info.id == id)
+            .cloned()
+    }
+
+    fn matching(&self, library: &Uuid, event: &str) -> Vec<Webhook> {
+        self.webhooks
+            .lock()
+            .unwrap()
+            .webhooks
+            .iter()
+            .filter(|v| &v.info.library == library)
+            .filter(|v| v.info.events.is_empty() || v.info.events.iter().any(|e| e == event))
+            .cloned()
+            .collect()
+    }
+
+    // Newest deliveries first
+    pub fn deliveries(&self, library: &Uuid, webhook: Option<u64>, limit: usize) -> Vec<Delivery> {
+        match self.deliveries.lock().unwrap().get(library) {
+            Some(log) => log
+                .iter()
+                .rev()
+                .filter(|v| webhook.map_or(true, |id| v.webhook == id))
+                .take(limit)
+                .cloned()
+                .collect(),
+            None => vec![],
+        }
+    }
+
+    fn record(&self, library: &Uuid, delivery: &Delivery) {
+        let mut deliveries = self.deliveries.lock().unwrap();
+        let log = deliveries.entry(*library).or_default();
+        match log.iter_mut().rev().find(|v| v.id == delivery.id) {
+            Some(v) => *v = delivery.clone(),
+            None => log.push_back(delivery.clone()),
+        }
+        while log.len() > self.config.log_size {
+            log.pop_front();
+        }
+    }
+}
+
+fn sign(secret: &str, body: &[u8]) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("hmac accepts keys of any length");
+    mac.update(body);
+    let digest = mac.finalize().into_bytes();
+    format!(
+        "sha256={}",
+        digest
+            .iter()
+            .map(|v| format!("{:02x}", v))
+            .collect::<String>()
+    )
+}
+
+// Post event to webhook, retried with exponential backoff until it is accepted by 2xx
+async fn deliver(
+    store: Arc<WebhookStore>,
+    client: Client,
+    webhook: Webhook,
+    event: String,
+    payload: serde_json::Value,
+) {
+    let library = webhook.info.library;
+    let mut delivery = Delivery {
+        id: store.next_delivery.fetch_add(1, Ordering::SeqCst),
+        webhook: webhook.info.id,
+        event,
+        status: DeliveryStatus::Pending,
+        attempts: 0,
+        response_status: None,
+        error: None,
+        created_at: now(),
+        updated_at: now(),
+    };
+    let body = serde_json::json!({
+        "delivery": delivery.id,
+        "webhook": delivery.webhook,
+        "timestamp": delivery.created_at,
+        "event": payload,
+    })
+    .to_string();
+    store.record(&library, &delivery);
+
+    let config = &store.config;
+    while delivery.attempts < config.max_attempts {
+        if delivery.attempts > 0 {
+            let backoff = config
+                .backoff
+                .saturating_mul(1 << (delivery.attempts - 1).min(16));
+            actix_web::rt::time::delay_for(Duration::from_secs(backoff)).await;
+        }
+        // url and secret are read again, webhook could be deleted while waiting
+        let webhook = match store.get(delivery.webhook) {
+            Some(v) => v,
+            None => break,
+        };
+        delivery.attempts += 1;
+        let result = client
+            .post(&webhook.info.url)
+            .timeout(Duration::from_secs(config.timeout))
+            .content_type("application/json")
+            .header(SIGNATURE_HEADER, sign(&webhook.secret, body.as_bytes()))
+            .header(EVENT_HEADER, delivery.event.as_str())
+            .header(DELIVERY_HEADER, delivery.id.to_string())
+            .send_body(body.clone())
+            .await;
+        let (response_status, error) = match result {
+            Ok(resp) if resp.status().is_success() => (Some(resp.status().as_u16()), None),
+            Ok(resp) => (
+                Some(resp.status().as_u16()),
+                Some(format!("Receiver answered with {}.", resp.status())),
+            ),
+            Err(e) => (None, Some(e.to_string())),
+        };
+        delivery.response_status = response_status;
+        delivery.updated_at = now();
+        if error.is_none() {
+            delivery.status = DeliveryStatus::Delivered;
+            delivery.error = None;
+            store.record(&library, &delivery);
+            return;
+        }
+        delivery.error = error;
+        store.record(&library, &delivery);
+    }
+    warn!(
+        "Gave up delivery {} of event {} to webhook {} after {} attempts.",
+        delivery.id, delivery.event, delivery.webhook, delivery.attempts
+    );
+    delivery.status = DeliveryStatus::Failed;
+    store.record(&library, &delivery);
+}
+
+// Deliver events of `events` to matching webhooks, runs on the current arbiter
+pub fn spawn_dispatcher(store: Arc<WebhookStore>, events: &EventHub) {
+    let mut receiver = events.subscribe();
+    actix_web::rt::spawn(async move {
+        let client = Client::default();
+        loop {
+            let event = match receiver.recv().await {
+                Ok(v) => v,
+                Err(RecvError::Lagged(missed)) => {
+                    warn!("Webhooks missed {} events due to slow dispatching.", missed);
+                    continue;
+                }
+                Err(RecvError::Closed) => break,
+            };
+            let payload = match serde_json::to_value(&event) {
+                Ok(v) => v,
+                Err(_) => continue,
+            };
+            let kind = payload["type"].as_str().unwrap_or_default().to_string();
+            for webhook in store.matching(event.library(), &kind) {
+                actix_web::rt::spawn(deliver(
+                    store.clone(),
+                    client.clone(),
+                    webhook,
+                    kind.clone(),
+                    payload.clone(),
+                ));
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::testing::TempDir;
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::time::Instant;
+
+    fn create_store(dir: &TempDir) -> Arc<WebhookStore> {
+        let config = WebhookConfig {
+            file: dir.path().join("webhooks.json"),
+            max_attempts: 3,
+            backoff: 1,
+            timeout: 5,
+            log_size: 10,
+        };
+        Arc::new(WebhookStore::load(&config).unwrap_or_else(|e| panic!("{}", e)))
+    }
+
+    // Headers with lowercase names and body of a http request
+    fn read_request(stream: &mut TcpStream) -> (HashMap<String, String>, String) {
+        let mut buf = vec![];
+        let mut chunk = [0; 1024];
+        let head_end = loop {
+            if let Some(v) = buf.windows(4).position(|v| v == b"\r\n\r\n") {
+                break v + 4;
+            }
+            let read = stream.read(&mut chunk).unwrap();
+            assert!(read > 0, "connection closed before headers");
+            buf.extend_from_slice(&chunk[..read]);
+        };
+        let headers = String::from_utf8_lossy(&buf[..head_end])
+            .lines()
+            .skip(1)
+            .filter_map(|v| {
+                let mut parts = v.splitn(2, ':');
+                Some((
+                    parts.next()?.trim().to_lowercase(),
+                    parts.next()?.trim().to_string(),
+                ))
+            })
+            .collect::<HashMap<_, _>>();
+        let length = headers
+            .get("content-length")
+            .and_then(|v| v.parse::<usize>().ok())
+            .unwrap_or(0);
+        while buf.len() < head_end + length {
+            let read = stream.read(&mut chunk).unwrap();
+            assert!(read > 0, "connection closed before body");
+            buf.extend_from_slice(&chunk[..read]);
+        }
+        let body = String::from_utf8_lossy(&buf[head_end..head_end + length]).to_string();
+        (headers, body)
+    }
+
+    #[actix_rt::test]
+    async fn delivery_is_signed_and_retried_with_backoff() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        // receiver fails the first attempt, so it is retried
+        let receiver = std::thread::spawn(move || {
+            ["500 Internal Server Error", "200 OK"]
+                .iter()
+                .map(|status| {
+                    let (mut stream, _) = listener.accept().unwrap();
+                    let request = read_request(&mut stream);
+                    write!(
+                        stream,
+                        "HTTP/1.1 {}\r\ncontent-length: 0\r\nconnection: close\r\n\r\n",
+                        status
+                    )
+                    .unwrap();
+                    (Instant::now(), request)
+                })
+                .collect::<Vec<_>>()
+        });
+
+        let dir = TempDir::new();
+        let store = create_store(&dir);
+        let library: Uuid = "00000000-0000-0000-0000-000000000001".parse().unwrap();
+        let info = store
+            .create(library, url, vec![], "secret".into())
+            .await
+            .unwrap_or_else(|e| panic!("{}", e));
+        let payload = serde_json::json!({"type": "media_added"});
+        let webhook = store.get(info.id).unwrap();
+        deliver(
+            store.clone(),
+            Client::default(),
+            webhook,
+            "media_added".into(),
+            payload,
+        )
+        .await;
+
+        let requests = receiver.join().unwrap();
+        for (_, (headers, body)) in &requests {
+            assert_eq!(
+                headers.get("x-shiromana-signature"),
+                Some(&sign("secret", body.as_bytes()))
+            );
+            assert_eq!(
+                headers.get("x-shiromana-event").map(|v| v.as_str()),
+                Some("media_added")
+            );
+            let body: serde_json::Value = serde_json::from_str(body).unwrap();
+            assert_eq!(body["event"]["type"], "media_added");
+        }
+        assert!(requests[1].0 - requests[0].0 >= Duration::from_secs(1));
+
+        let delivery = &store.deliveries(&library, Some(info.id), 1)[0];
+        assert!(delivery.status == DeliveryStatus::Delivered);
+        assert_eq!(delivery.attempts, 2);
+        assert_eq!(delivery.response_status, Some(200));
+    }
+
+    #[actix_rt::test]
+    async fn ids_of_deleted_webhooks_are_not_reused() {
+        let dir = TempDir::new();
+        let store = create_store(&dir);
+        let library: Uuid = "00000000-0000-0000-0000-000000000001".parse().unwrap();
+        let create = |store: Arc<WebhookStore>| async move {
+            store
+                .create(library, "http://localhost/".into(), vec![], "s".into())
+                .await
+                .unwrap_or_else(|e| panic!("{}", e))
+                .id
+        };
+        let (first, second) = (create(store.clone()).await, create(store.clone()).await);
+        assert!(store.delete(&library, second).await.unwrap_or(false));
+        // as after a restart
+        let store = WebhookStore::load(&store.config).unwrap_or_else(|e| panic!("{}", e));
+        let third = create(Arc::new(store)).await;
+        assert!(third != first && third != second);
+    }
+}
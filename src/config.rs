@@ -0,0 +1,404 @@
+use clap::ArgMatches;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+const ENV_PREFIX: &str = "SHIROMANA_";
+
+pub enum ConfigError {
+    Io(PathBuf, std::io::Error),
+    Parse(PathBuf, toml::de::Error),
+    Invalid {
+        key: String,
+        got: String,
+        expect: String,
+    },
+}
+
+impl std::fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Io(path, err) => write!(f, "Cannot read config file {:?}: {}", path, err),
+            Self::Parse(path, err) => write!(f, "Invalid config file {:?}: {}", path, err),
+            Self::Invalid { key, got, expect } => write!(
+                f,
+                "Config `{}` with value `{}` cannot be parsed to `{}`.",
+                key, got, expect
+            ),
+        }
+    }
+}
+
+pub type Result<T> = std::result::Result<T, ConfigError>;
+
+#[derive(Deserialize, Serialize, Clone)]
+#[serde(deny_unknown_fields, default)]
+pub struct UploadConfig {
+    // Maximum size of an uploaded file in bytes
+    pub max_size: u64,
+    // Folder storing unfinished resumable uploads, temp folder of system if not provided
+    pub dir: Option<PathBuf>,
+    // Unfinished resumable uploads are removed after this many seconds of inactivity
+    pub session_timeout: u64,
+}
+
+impl Default for UploadConfig {
+    fn default() -> Self {
+        Self {
+            max_size: 4 * 1024 * 1024 * 1024,
+            dir: None,
+            session_timeout: 24 * 60 * 60,
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone)]
+#[serde(deny_unknown_fields, default)]
+pub struct ThumbnailConfig {
+    // Seconds clients could cache thumbnails, `Cache-Control: max-age`
+    pub cache_max_age: u32,
+}
+
+impl Default for ThumbnailConfig {
+    fn default() -> Self {
+        Self {
+            cache_max_age: 7 * 24 * 60 * 60,
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone)]
+#[serde(deny_unknown_fields, default)]
+pub struct ImageConfig {
+    // Largest width or height of images resized by `{lib}/{media}/image`
+    pub max_dimension: u32,
+    // Folder caching resized images, temp folder of system if not provided
+    pub cache_dir: Option<PathBuf>,
+    // Bytes of resized images cached, least recently used ones are removed beyond it
+    pub cache_size: u64,
+    // Quality of jpeg if not given
+    pub quality: u8,
+}
+
+impl Default for ImageConfig {
+    fn default() -> Self {
+        Self {
+            max_dimension: 4096,
+            cache_dir: None,
+            cache_size: 512 * 1024 * 1024,
+            quality: 85,
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone)]
+#[serde(deny_unknown_fields, default)]
+pub struct ExecutorConfig {
+    // Maximum library operations queued or running on blocking threads,
+    // requests beyond it are answered with 503
+    pub max_pending: usize,
+}
+
+impl Default for ExecutorConfig {
+    fn default() -> Self {
+        Self { max_pending: 64 }
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone)]
+#[serde(deny_unknown_fields, default)]
+pub struct WebhookConfig {
+    // Json file of webhooks registered through apis
+    pub file: PathBuf,
+    // Attempts of a delivery before it is given up
+    pub max_attempts: u32,
+    // Seconds before the first retry, doubled after each failed attempt
+    pub backoff: u64,
+    // Seconds to wait for the receiver to answer
+    pub timeout: u64,
+    // Deliveries kept in log of each library
+    pub log_size: usize,
+}
+
+impl Default for WebhookConfig {
+    fn default() -> Self {
+        Self {
+            file: PathBuf::from("shiromana-webhooks.json"),
+            max_attempts: 5,
+            backoff: 2,
+            timeout: 10,
+            log_size: 200,
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone)]
+#[serde(deny_unknown_fields, default)]
+pub struct JobConfig {
+    // Background jobs running at the same time on each library, others are queued
+    pub concurrency: usize,
+    // Finished jobs kept for `jobs/get` and `jobs/list`
+    pub history: usize,
+    // Json file of where interrupted jobs stopped, so they could resume after restart
+    pub file: PathBuf,
+}
+
+impl Default for JobConfig {
+    fn default() -> Self {
+        Self {
+            concurrency: 2,
+            history: 200,
+            file: PathBuf::from("shiromana-jobs.json"),
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone)]
+#[serde(deny_unknown_fields, default)]
+pub struct ServerConfig {
+    pub listen: Vec<SocketAddr>,
+    pub workers: Option<usize>,
+    // Filter of env_logger, like `info` or `shiromana_server=debug`
+    pub log_level: String,
+    // Folders which library and media paths must be under, any path if empty
+    pub allowed_roots: Vec<PathBuf>,
+    // Json file of api tokens, authentication is disabled if not provided
+    pub tokens: Option<PathBuf>,
+    // Libraries opened at startup
+    pub libraries: Vec<PathBuf>,
+    // Json file recording libraries opened or created through apis
+    pub registry: PathBuf,
+    pub upload: UploadConfig,
+    pub thumbnail: ThumbnailConfig,
+    // `Cache-Control` of successful responses by route like `{lib}/{media}/media`,
+    // replacing the one set by route itself
+    pub cache_control: BTreeMap<String, String>,
+    pub image: ImageConfig,
+    pub executor: ExecutorConfig,
+    pub webhooks: WebhookConfig,
+    pub jobs: JobConfig,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            listen: vec![SocketAddr::from(([127, 0, 0, 1], 22110))],
+            workers: None,
+            log_level: "info".into(),
+            allowed_roots: vec![],
+            tokens: None,
+            libraries: vec![],
+            registry: PathBuf::from("shiromana-registry.json"),
+            upload: UploadConfig::default(),
+            thumbnail: ThumbnailConfig::default(),
+            cache_control: BTreeMap::new(),
+            image: ImageConfig::default(),
+            executor: ExecutorConfig::default(),
+            webhooks: WebhookConfig::default(),
+            jobs: JobConfig::default(),
+        }
+    }
+}
+
+pub const ENV_KEYS: [&str; 25] = [
+    "SHIROMANA_LISTEN",
+    "SHIROMANA_WORKERS",
+    "SHIROMANA_LOG_LEVEL",
+    "SHIROMANA_ALLOWED_ROOTS",
+    "SHIROMANA_TOKENS",
+    "SHIROMANA_LIBRARIES",
+    "SHIROMANA_REGISTRY",
+    "SHIROMANA_UPLOAD_MAX_SIZE",
+    "SHIROMANA_UPLOAD_DIR",
+    "SHIROMANA_UPLOAD_SESSION_TIMEOUT",
+    "SHIROMANA_THUMBNAIL_CACHE_MAX_AGE",
+    "SHIROMANA_CACHE_CONTROL",
+    "SHIROMANA_IMAGE_MAX_DIMENSION",
+    "SHIROMANA_IMAGE_CACHE_DIR",
+    "SHIROMANA_IMAGE_CACHE_SIZE",
+    "SHIROMANA_IMAGE_QUALITY",
+    "SHIROMANA_EXECUTOR_MAX_PENDING",
+    "SHIROMANA_WEBHOOKS_FILE",
+    "SHIROMANA_WEBHOOKS_MAX_ATTEMPTS",
+    "SHIROMANA_WEBHOOKS_BACKOFF",
+    "SHIROMANA_WEBHOOKS_TIMEOUT",
+    "SHIROMANA_WEBHOOKS_LOG_SIZE",
+    "SHIROMANA_JOBS_CONCURRENCY",
+    "SHIROMANA_JOBS_HISTORY",
+    "SHIROMANA_JOBS_FILE",
+];
+
+fn parse<T: FromStr>(key: &str, value: &str) -> Result<T> {
+    value.parse::<T>().map_err(|_| ConfigError::Invalid {
+        key: key.to_string(),
+        got: value.to_string(),
+        expect: std::any::type_name::<T>().to_string(),
+    })
+}
+
+fn parse_list<T: FromStr>(key: &str, value: &str) -> Result<Vec<T>> {
+    value
+        .split(',')
+        .map(|v| v.trim())
+        .filter(|v| !v.is_empty())
+        .map(|v| parse(key, v))
+        .collect()
+}
+
+impl ServerConfig {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let content =
+            std::fs::read_to_string(path).map_err(|e| ConfigError::Io(path.to_owned(), e))?;
+        toml::from_str(&content).map_err(|e| ConfigError::Parse(path.to_owned(), e))
+    }
+
+    // Override config by `SHIROMANA_*` environment variables, returns unknown ones
+    pub fn apply_env<I: IntoIterator<Item = (String, String)>>(
+        &mut self,
+        vars: I,
+    ) -> Result<Vec<String>> {
+        let mut unknown = vec![];
+        for (key, value) in vars {
+            if !key.starts_with(ENV_PREFIX) {
+                continue;
+            }
+            match key.as_str() {
+                "SHIROMANA_LISTEN" => self.listen = parse_list(&key, &value)?,
+                "SHIROMANA_WORKERS" => self.workers = Some(parse(&key, &value)?),
+                "SHIROMANA_LOG_LEVEL" => self.log_level = value,
+                "SHIROMANA_ALLOWED_ROOTS" => {
+                    self.allowed_roots = std::env::split_paths(&value).collect()
+                }
+                "SHIROMANA_TOKENS" => self.tokens = Some(PathBuf::from(value)),
+                "SHIROMANA_LIBRARIES" => self.libraries = std::env::split_paths(&value).collect(),
+                "SHIROMANA_REGISTRY" => self.registry = PathBuf::from(value),
+                "SHIROMANA_UPLOAD_MAX_SIZE" => self.upload.max_size = parse(&key, &value)?,
+                "SHIROMANA_UPLOAD_DIR" => self.upload.dir = Some(PathBuf::from(value)),
+                "SHIROMANA_UPLOAD_SESSION_TIMEOUT" => {
+                    self.upload.session_timeout = parse(&key, &value)?
+                }
+                "SHIROMANA_THUMBNAIL_CACHE_MAX_AGE" => {
+                    self.thumbnail.cache_max_age = parse(&key, &value)?
+                }
+                // like `{lib}/{media}/media=max-age=3600;{lib}/{media}/thumbnail=no-cache`
+                "SHIROMANA_CACHE_CONTROL" => {
+                    self.cache_control = value
+                        .split(';')
+                        .filter(|v| !v.trim().is_empty())
+                        .map(|v| match v.split_once('=') {
+                            Some((route, policy)) => {
+                                Ok((route.trim().to_string(), policy.trim().to_string()))
+                            }
+                            None => Err(ConfigError::Invalid {
+                                key: key.clone(),
+                                got: v.to_string(),
+                                expect: "route=policy".into(),
+                            }),
+                        })
+                        .collect::<Result<_>>()?
+                }
+                "SHIROMANA_IMAGE_MAX_DIMENSION" => self.image.max_dimension = parse(&key, &value)?,
+                "SHIROMANA_IMAGE_CACHE_DIR" => self.image.cache_dir = Some(PathBuf::from(value)),
+                "SHIROMANA_IMAGE_CACHE_SIZE" => self.image.cache_size = parse(&key, &value)?,
+                "SHIROMANA_IMAGE_QUALITY" => self.image.quality = parse(&key, &value)?,
+                "SHIROMANA_EXECUTOR_MAX_PENDING" => {
+                    self.executor.max_pending = parse(&key, &value)?
+                }
+                "SHIROMANA_WEBHOOKS_FILE" => self.webhooks.file = PathBuf::from(value),
+                "SHIROMANA_WEBHOOKS_MAX_ATTEMPTS" => {
+                    self.webhooks.max_attempts = parse(&key, &value)?
+                }
+                "SHIROMANA_WEBHOOKS_BACKOFF" => self.webhooks.backoff = parse(&key, &value)?,
+                "SHIROMANA_WEBHOOKS_TIMEOUT" => self.webhooks.timeout = parse(&key, &value)?,
+                "SHIROMANA_WEBHOOKS_LOG_SIZE" => self.webhooks.log_size = parse(&key, &value)?,
+                "SHIROMANA_JOBS_CONCURRENCY" => self.jobs.concurrency = parse(&key, &value)?,
+                "SHIROMANA_JOBS_HISTORY" => self.jobs.history = parse(&key, &value)?,
+                "SHIROMANA_JOBS_FILE" => self.jobs.file = PathBuf::from(value),
+                _ => unknown.push(key),
+            }
+        }
+        Ok(unknown)
+    }
+
+    // Override config by arguments given in command line
+    pub fn apply_args(&mut self, matches: &ArgMatches) -> Result<()> {
+        if let Some(v) = matches.values_of("host") {
+            self.listen = v.map(|v| parse("host", v)).collect::<Result<_>>()?;
+        }
+        if let Some(v) = matches.value_of("workers") {
+            self.workers = Some(parse("workers", v)?);
+        }
+        if let Some(v) = matches.value_of("max-upload-size") {
+            self.upload.max_size = parse("max-upload-size", v)?;
+        }
+        if let Some(v) = matches.value_of("upload-dir") {
+            self.upload.dir = Some(PathBuf::from(v));
+        }
+        if let Some(v) = matches.value_of("upload-timeout") {
+            self.upload.session_timeout = parse("upload-timeout", v)?;
+        }
+        if let Some(v) = matches.value_of("tokens") {
+            self.tokens = Some(PathBuf::from(v));
+        }
+        if let Some(v) = matches.values_of("allowed-root") {
+            self.allowed_roots = v.map(PathBuf::from).collect();
+        }
+        Ok(())
+    }
+
+    // Check values which are only used when serving requests
+    pub fn validate(&self) -> Result<()> {
+        if !(1..=100).contains(&self.image.quality) {
+            return Err(ConfigError::Invalid {
+                key: "image.quality".into(),
+                got: self.image.quality.to_string(),
+                expect: "quality between 1 and 100".into(),
+            });
+        }
+        if self.workers == Some(0) {
+            return Err(ConfigError::Invalid {
+                key: "workers".into(),
+                got: "0".into(),
+                expect: "at least 1 worker".into(),
+            });
+        }
+        if self.executor.max_pending == 0 {
+            return Err(ConfigError::Invalid {
+                key: "executor.max_pending".into(),
+                got: "0".into(),
+                expect: "at least 1 pending operation".into(),
+            });
+        }
+        for (route, policy) in self.cache_control.iter() {
+            if actix_web::http::HeaderValue::from_str(policy).is_err() {
+                return Err(ConfigError::Invalid {
+                    key: format!("cache_control.{}", route),
+                    got: policy.clone(),
+                    expect: "header value".into(),
+                });
+            }
+        }
+        Ok(())
+    }
+
+    pub fn upload_dir(&self) -> PathBuf {
+        match &self.upload.dir {
+            Some(v) => v.clone(),
+            None => std::env::temp_dir().join("shiromana-uploads"),
+        }
+    }
+
+    pub fn image_cache_dir(&self) -> PathBuf {
+        match &self.image.cache_dir {
+            Some(v) => v.clone(),
+            None => std::env::temp_dir().join("shiromana-images"),
+        }
+    }
+
+    pub fn to_toml_string(&self) -> String {
+        toml::to_string_pretty(self).unwrap_or_else(|e| format!("<cannot serialize: {}>", e))
+    }
+}
@@ -3,37 +3,36 @@
 
 use std::collections::HashMap;
 use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
 use std::sync::Arc;
+use std::time::Duration;
 
-use actix_web::middleware::Logger;
+use actix_web::middleware::{Condition, Logger};
 use actix_web::{get, route, web, App, HttpRequest, HttpResponse, HttpServer, Responder};
 use clap::{App as clapApp, Arg};
 use env_logger::Env;
-use log::info;
+use log::{error, info, warn};
 use shiromana_rs::library::Library;
 use shiromana_rs::misc::Uuid;
 use tokio::sync::mpsc::Sender;
 use tokio::sync::Mutex;
 
 mod api;
+mod config;
 mod versions;
 
-pub struct AppState {
-    pub opened_libraries: Arc<Mutex<HashMap<Uuid, Library>>>,
-}
+use config::ServerConfig;
 
-struct ServerConfig {
-    host: IpAddr,
-    port: u16,
-}
-
-impl Default for ServerConfig {
-    fn default() -> Self {
-        ServerConfig {
-            host: IpAddr::from([127, 0, 0, 1]),
-            port: 22110,
-        }
-    }
+pub struct AppState {
+    pub opened_libraries: Arc<api::libraries::OpenedLibraries>,
+    pub config: Arc<ServerConfig>,
+    pub uploads: Arc<api::upload::UploadStore>,
+    pub images: Arc<api::images::ImageCache>,
+    pub allowed_roots: Arc<api::paths::AllowedRoots>,
+    pub registry: Arc<api::registry::LibraryRegistry>,
+    pub events: Arc<api::events::EventHub>,
+    pub webhooks: Arc<api::webhooks::WebhookStore>,
+    pub jobs: Arc<api::jobs::JobManager>,
 }
 
 #[get("/")]
@@ -74,15 +73,24 @@ async fn main() -> std::io::Result<()> {
         .version("0.1.1")
         .about("Shiromana Media Manager Http Server of APIs.")
         .author("Shiroko <hhx.xxm@gmail.com>")
+        .arg(
+            Arg::with_name("config")
+                .short("c")
+                .long("config")
+                .value_name("FILE")
+                .help("Server's config file in toml.")
+                .takes_value(true)
+                .multiple(false),
+        )
         .arg(
             Arg::with_name("host")
                 .short("H")
                 .long("host")
                 .value_name("IP:PORT")
-                .help("Server's listen address with port.")
+                .help("Server's listen address with port. Could be repeated.")
                 .takes_value(true)
-                .multiple(false)
-                .default_value("127.0.0.1:22110")
+                .multiple(true)
+                .number_of_values(1)
                 .validator(|v| {
                     if v.parse::<SocketAddr>().is_err() {
                         return Err("Host must be like `127.0.0.1:22110`".to_string());
@@ -90,40 +98,294 @@ async fn main() -> std::io::Result<()> {
                     return Ok(());
                 }),
         )
+        .arg(
+            Arg::with_name("max-upload-size")
+                .long("max-upload-size")
+                .value_name("BYTES")
+                .help("Maximum size of a file uploaded through `media/upload`.")
+                .takes_value(true)
+                .multiple(false)
+                .validator(|v| {
+                    if v.parse::<u64>().is_err() {
+                        return Err("Upload size must be a number of bytes".to_string());
+                    }
+                    return Ok(());
+                }),
+        )
+        .arg(
+            Arg::with_name("upload-dir")
+                .long("upload-dir")
+                .value_name("PATH")
+                .help("Folder storing unfinished resumable uploads.")
+                .takes_value(true)
+                .multiple(false),
+        )
+        .arg(
+            Arg::with_name("upload-timeout")
+                .long("upload-timeout")
+                .value_name("SECONDS")
+                .help("Remove unfinished resumable uploads after this many seconds of inactivity.")
+                .takes_value(true)
+                .multiple(false)
+                .validator(|v| {
+                    if v.parse::<u64>().is_err() {
+                        return Err("Upload timeout must be a number of seconds".to_string());
+                    }
+                    return Ok(());
+                }),
+        )
+        .arg(
+            Arg::with_name("workers")
+                .short("w")
+                .long("workers")
+                .value_name("NUM")
+                .help("Number of http workers, count of cpu cores by default.")
+                .takes_value(true)
+                .multiple(false)
+                .validator(|v| {
+                    if v.parse::<usize>().is_err() {
+                        return Err("Workers must be a number".to_string());
+                    }
+                    return Ok(());
+                }),
+        )
+        .arg(
+            Arg::with_name("tokens")
+                .long("tokens")
+                .value_name("FILE")
+                .help("Json file of api tokens. Authentication is disabled if not provided.")
+                .takes_value(true)
+                .multiple(false),
+        )
+        .arg(
+            Arg::with_name("allowed-root")
+                .long("allowed-root")
+                .value_name("PATH")
+                .help("Folder which library and media paths must be under. Could be repeated.")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1),
+        )
         .get_matches();
+    // load config, from file, then environment variables and arguments
+    let mut config = match matches.value_of("config") {
+        Some(path) => match ServerConfig::load(path) {
+            Ok(v) => v,
+            Err(e) => {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+        },
+        None => ServerConfig::default(),
+    };
+    let unknown_env = match config.apply_env(std::env::vars()).and_then(|unknown| {
+        config.apply_args(&matches)?;
+        config.validate()?;
+        Ok(unknown)
+    }) {
+        Ok(v) => v,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+    let config = Arc::new(config);
     // setup logger
-    env_logger::Builder::from_env(Env::default().default_filter_or("info"))
+    env_logger::Builder::from_env(Env::default().default_filter_or(config.log_level.as_str()))
         .target(env_logger::Target::Stdout)
         .write_style(env_logger::WriteStyle::Always)
         .init();
-    // setup libraries shared Mutex
-    let opened_libraries = Arc::new(Mutex::new(HashMap::new()));
+    for key in unknown_env.iter() {
+        warn!(
+            "Ignored unknown environment variable `{}`, known ones are: {}.",
+            key,
+            config::ENV_KEYS.join(", ")
+        );
+    }
+    info!("Effective config:\n{}", config.to_toml_string());
+    let allowed_roots = match api::paths::AllowedRoots::new(config.allowed_roots.iter()) {
+        Ok(v) => Arc::new(v),
+        Err(e) => {
+            error!("Cannot resolve allowed roots: {}", e);
+            std::process::exit(1);
+        }
+    };
+    if allowed_roots.is_unrestricted() {
+        warn!("No allowed root provided, apis could access any path of server.");
+    } else {
+        info!("Allowed roots: {:?}", allowed_roots.roots());
+    }
+    let registry = match api::registry::LibraryRegistry::load(&config.registry) {
+        Ok(v) => Arc::new(v),
+        Err(e) => {
+            error!("Cannot load library registry {:?}: {}", config.registry, e);
+            std::process::exit(1);
+        }
+    };
+    // setup opened libraries
+    let opened_libraries = Arc::new(api::libraries::OpenedLibraries::new(
+        config.executor.max_pending,
+    ));
     let clone_of_opened_libraries = opened_libraries.clone();
-    // start server
-    // let server_config = ServerConfig::default();
-    // let listen_addr = SocketAddr::new(server_config.host, server_config.port);
-    let listen_addr: SocketAddr = matches.value_of("host").unwrap().parse().unwrap();
+    {
+        let registered = registry.entries();
+        let paths = config
+            .libraries
+            .iter()
+            .map(|v| (v.to_string_lossy().to_string(), None))
+            .chain(registered.into_iter().map(|v| (v.path, Some(v.uuid))));
+        for (path, registered_uuid) in paths {
+            // same as `library/open`, roots may have changed since library was registered
+            let path = match allowed_roots.check("path", &path) {
+                Ok(v) => v.to_string_lossy().to_string(),
+                Err(e) => {
+                    error!("Skipped library at {}: {}", path, e);
+                    continue;
+                }
+            };
+            match Library::open(path.clone()) {
+                Ok(lib) => {
+                    if let Some(uuid) = registered_uuid.filter(|v| v != &lib.uuid) {
+                        warn!(
+                            "Library at {} was registered as {} but now is {}.",
+                            path, uuid, lib.uuid
+                        );
+                    }
+                    let uuid = lib.uuid.clone();
+                    if opened_libraries.insert(lib) {
+                        info!("Opened library {} at {}.", uuid, path);
+                    }
+                }
+                Err(e) => error!("Cannot open library at {}: {}", path, e),
+            }
+        }
+    }
+    let uploads = match api::upload::UploadStore::new(
+        config.upload_dir(),
+        Duration::from_secs(config.upload.session_timeout),
+        config.upload.max_size,
+    ) {
+        Ok(v) => Arc::new(v),
+        Err(e) => {
+            error!("Cannot use upload folder {:?}: {}", config.upload_dir(), e);
+            std::process::exit(1);
+        }
+    };
+    let images = match api::images::ImageCache::new(
+        config.image_cache_dir(),
+        config.image.cache_size,
+    ) {
+        Ok(v) => Arc::new(v),
+        Err(e) => {
+            error!(
+                "Cannot use image cache folder {:?}: {}",
+                config.image_cache_dir(),
+                e
+            );
+            std::process::exit(1);
+        }
+    };
+    let tokens = match &config.tokens {
+        Some(path) => match api::auth::TokenStore::load(path) {
+            Ok(v) => {
+                info!("Loaded {} api tokens from {:?}.", v.len(), path);
+                Some(Arc::new(v))
+            }
+            Err(e) => {
+                error!("Cannot load api tokens from {:?}: {}", path, e);
+                std::process::exit(1);
+            }
+        },
+        None => {
+            warn!("No api tokens provided, authentication is disabled.");
+            None
+        }
+    };
+    // clean up expired upload sessions
+    {
+        let uploads = uploads.clone();
+        actix_web::rt::spawn(async move {
+            let mut interval = actix_web::rt::time::interval(Duration::from_secs(60));
+            loop {
+                interval.tick().await;
+                let uploads = uploads.clone();
+                let _ = api::libraries::block(move || Ok(uploads.collect_garbage())).await;
+            }
+        });
+    }
 
-    HttpServer::new(move || {
+    let events = Arc::new(api::events::EventHub::default());
+    let webhooks = match api::webhooks::WebhookStore::load(&config.webhooks) {
+        Ok(v) => {
+            info!(
+                "Loaded {} webhooks from {:?}.",
+                v.len(),
+                config.webhooks.file
+            );
+            Arc::new(v)
+        }
+        Err(e) => {
+            error!(
+                "Cannot load webhooks from {:?}: {}",
+                config.webhooks.file, e
+            );
+            std::process::exit(1);
+        }
+    };
+    api::webhooks::spawn_dispatcher(webhooks.clone(), &events);
+    let jobs = match api::jobs::JobManager::load(&config.jobs) {
+        Ok(v) => Arc::new(v),
+        Err(e) => {
+            error!("Cannot load jobs from {:?}: {}", config.jobs.file, e);
+            std::process::exit(1);
+        }
+    };
+
+    let server_config = config.clone();
+    let mut server = HttpServer::new(move || {
         App::new()
             .wrap(Logger::default())
             .data(AppState {
                 opened_libraries: opened_libraries.clone(),
+                config: config.clone(),
+                uploads: uploads.clone(),
+                images: images.clone(),
+                allowed_roots: allowed_roots.clone(),
+                registry: registry.clone(),
+                events: events.clone(),
+                webhooks: webhooks.clone(),
+                jobs: jobs.clone(),
             })
             .service(root)
-            .service(web::scope("/api").configure(api::service_config))
-    })
-    //.workers(1)
-    .bind(listen_addr)?
-    .run()
-    .await;
+            .service(
+                web::scope("/api/v2")
+                    .data(api::ApiVersion::V2)
+                    .wrap(Condition::new(
+                        tokens.is_some(),
+                        api::auth::TokenAuth::new(tokens.clone().unwrap_or_default()),
+                    ))
+                    .configure(api::service_config),
+            )
+            .service(
+                web::scope("/api")
+                    .wrap(Condition::new(
+                        tokens.is_some(),
+                        api::auth::TokenAuth::new(tokens.clone().unwrap_or_default()),
+                    ))
+                    .configure(api::service_config),
+            )
+    });
+    if let Some(workers) = server_config.workers {
+        server = server.workers(workers);
+    }
+    for listen_addr in server_config.listen.iter() {
+        server = server.bind(listen_addr)?;
+    }
+    server.run().await;
 
     info!("Http server is shutting down.");
     info!("Running clean up routine");
-    {
-        let mut opened_libraries = clone_of_opened_libraries.lock().await;
-        opened_libraries.clear();
-    }
+    clone_of_opened_libraries.clear();
     info!("Bye, see you next time~");
     Ok(())
 }